@@ -33,6 +33,9 @@ export PATH=`pwd`/ykrustc-stage2-latest/bin:${PATH}
 
 cargo test
 
+# ykpack's types must also build without std.
+(cd ykpack && cargo check --no-default-features)
+
 # Although it might be tempting to test release mode, we have (for now) made
 # the compiler to crash if optimisations *and* a tracer are enabled.
 #cargo test --release
@@ -16,14 +16,15 @@ use libc::{c_void, dlsym, RTLD_DEFAULT};
 use stack_builder::StackBuilder;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::error::Error;
 use std::fmt::{self, Display, Formatter};
 use std::mem;
 use std::process::Command;
-use ykpack::{SignedIntTy, Ty, TypeId, UnsignedIntTy};
+use ykpack::{AbiClass, SignedIntTy, Ty, TypeId, UnsignedIntTy};
 use yktrace::sir::SIR;
 use yktrace::tir::{
-    BinOp, CallOperand, Constant, ConstantInt, Guard, Local, Operand, Place, Projection, Rvalue,
-    Statement, TirOp, TirTrace,
+    BinOp, CallAbi, CallOperand, Constant, ConstantInt, Guard, Local, Operand, Place, Projection,
+    Rvalue, Statement, TirOp, TirTrace,
 };
 
 use dynasmrt::{DynasmApi, DynasmLabelApi};
@@ -35,6 +36,9 @@ pub enum CompileError {
     /// Skip compilation of all further statements.
     /// We use this when we see the call to `ThreadTracer:stop_tracing()`.
     NoFurtherStatements,
+    /// The trace contains something which the compiler can't handle yet.
+    /// The string inside describes what.
+    Unsupported(String),
 }
 
 impl Display for CompileError {
@@ -42,10 +46,13 @@ impl Display for CompileError {
         match self {
             Self::UnknownSymbol(s) => write!(f, "Unknown symbol: {}", s),
             Self::NoFurtherStatements => write!(f, "No further statements"),
+            Self::Unsupported(s) => write!(f, "Unsupported: {}", s),
         }
     }
 }
 
+impl Error for CompileError {}
+
 /// Converts a register number into it's string name.
 fn local_to_reg_name(loc: &Location) -> &'static str {
     match loc {
@@ -161,8 +168,11 @@ impl<TT> TraceCompiler<TT> {
                 SignedIntTy::I128 => false,
                 _ => true,
             },
-            Ty::Ref(_) | Ty::Bool => true,
-            Ty::Struct(..) | Ty::Tuple(..) => false,
+            Ty::Ref(_) | Ty::FnPtr | Ty::Bool | Ty::Char => true,
+            // FIXME: floats should live in SSE registers.
+            Ty::Float(_) => false,
+            // A fat reference needs two registers.
+            Ty::FatRef(_) | Ty::Struct(..) | Ty::Tuple(..) | Ty::Array(..) | Ty::Enum(..) => false,
             Ty::Unimplemented(..) => todo!("{}", ty),
         }
     }
@@ -181,7 +191,9 @@ impl<TT> TraceCompiler<TT> {
                             let offs = tty.fields.offsets[usize::try_from(*idx).unwrap()];
                             Ok(Location::new_mem(RDI.code(), i32::try_from(offs).unwrap()))
                         }
-                        Projection::Deref => unreachable!(),
+                        Projection::Deref
+                        | Projection::Index(_)
+                        | Projection::ConstantIndex { .. } => unreachable!(),
                         Projection::Unimplemented(s) => todo!("{}", s),
                     }
                 } else {
@@ -286,22 +298,10 @@ impl<TT> TraceCompiler<TT> {
 
     /// Get the type of a place.
     fn place_ty(&self, p: &Place) -> &Ty {
-        let base_ty = SIR.ty(&self.local_decls[&p.local].ty);
-        if p.projection.is_empty() {
-            base_ty
-        } else {
-            // FIXME this is just hacked in for now. There should be a loop which resolves
-            // arbitrarily long chains of projections.
-            assert_eq!(p.projection.len(), 1);
-            match p.projection[0] {
-                Projection::Field(idx) => match base_ty {
-                    Ty::Struct(sty) => SIR.ty(&sty.fields.tys[usize::try_from(idx).unwrap()]),
-                    Ty::Tuple(tty) => SIR.ty(&tty.fields.tys[usize::try_from(idx).unwrap()]),
-                    t => todo!("{:?}", t),
-                },
-                _ => todo!("place_ty() for projection: {:?}", p.projection),
-            }
-        }
+        let tyid = p
+            .resolved_ty(|l| self.local_decls.get(l), |t| SIR.get_ty(t))
+            .unwrap_or_else(|e| panic!("couldn't resolve type of {}: {}", p, e));
+        SIR.ty(&tyid)
     }
 
     /// Copy the contents of the place `p2` into `p1`.
@@ -636,22 +636,40 @@ impl<TT> TraceCompiler<TT> {
     ///  - We assume the return value fits in rax. 128-bit return values are not yet supported.
     ///
     ///  - We don't support varags calls.
+    ///
+    ///  - We don't support indirect calls (through a function pointer).
+    ///
+    ///  - We only support arguments and return values of the INTEGER ABI class.
     fn c_call(
         &mut self,
         opnd: &CallOperand,
         args: &Vec<Operand>,
         dest: &Option<Place>,
+        abi: &CallAbi,
     ) -> Result<(), CompileError> {
         let sym = if let CallOperand::Fn(sym) = opnd {
             sym
         } else {
-            todo!("unknown call target");
+            return Err(CompileError::Unsupported(format!("call to {}", opnd)));
         };
 
         if args.len() > 6 {
             todo!("call with spilled args");
         }
 
+        if abi.args.contains(&AbiClass::Memory) || abi.ret == Some(AbiClass::Memory) {
+            return Err(CompileError::Unsupported(format!(
+                "call to {} with arguments or return value passed in memory",
+                sym
+            )));
+        }
+        if abi.args.contains(&AbiClass::Sse) || abi.ret == Some(AbiClass::Sse) {
+            return Err(CompileError::Unsupported(format!(
+                "call to {} with arguments or return value passed in SSE registers",
+                sym
+            )));
+        }
+
         // Figure out where the return value (if there is one) is going.
         let dest_location: Option<Location> = if let Some(d) = dest {
             Some(self.place_to_location(d)?)
@@ -721,8 +739,11 @@ impl<TT> TraceCompiler<TT> {
                     };
                 }
                 Operand::Constant(c) => {
+                    let c_val = c
+                        .i64_cast()
+                        .ok_or_else(|| CompileError::Unsupported(format!("call argument {}", c)))?;
                     dynasm!(self.asm
-                        ; mov Rq(arg_reg), QWORD c.i64_cast()
+                        ; mov Rq(arg_reg), QWORD c_val
                     );
                 }
             };
@@ -945,7 +966,7 @@ impl<TT> TraceCompiler<TT> {
             Statement::Enter(op, args, dest, off) => self.c_enter(op, args, dest, *off)?,
             Statement::Leave => {}
             Statement::StorageDead(l) => self.free_register(l)?,
-            Statement::Call(target, args, dest) => self.c_call(target, args, dest)?,
+            Statement::Call(target, args, dest, abi) => self.c_call(target, args, dest, abi)?,
             Statement::Nop => {}
             Statement::Unimplemented(s) => todo!("{:?}", s),
         }
@@ -1134,7 +1155,8 @@ mod tests {
     use libc::{abs, c_void, getuid};
     use regex::Regex;
     use std::marker::PhantomData;
-    use yktrace::tir::TirTrace;
+    use ykpack::{AbiClass, Fields, SizeAndAlign, StructTy, Ty, UnsignedIntTy};
+    use yktrace::tir::{CallAbi, CallOperand, Operand, Place, TirTrace};
     use yktrace::{start_tracing, TracingKind};
 
     extern "C" {
@@ -1286,6 +1308,31 @@ mod tests {
         assert_eq!(args.0, 20);
     }
 
+    // A recursive function, so that the same symbol is inlined at several depths.
+    #[inline(never)]
+    fn frec(n: u8) -> u8 {
+        match n {
+            0 => 1,
+            1 => frec(0) + 1,
+            2 => frec(1) + 1,
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn test_function_call_recursive() {
+        let mut inputs = trace_inputs((0,));
+        let th = start_tracing(Some(TracingKind::HardwareTracing));
+        inputs.0 = frec(2);
+        let sir_trace = th.stop_tracing().unwrap();
+        let tir_trace = TirTrace::new(&*sir_trace).unwrap();
+        let ct = TraceCompiler::<&(u8,)>::compile(tir_trace);
+        let mut args = (0,);
+        ct.execute(&mut args);
+        assert_eq!(args.0, frec(2));
+        assert_eq!(args.0, 3);
+    }
+
     // Test finding a symbol in a shared object.
     #[test]
     fn find_symbol_shared() {
@@ -1313,6 +1360,72 @@ mod tests {
         );
     }
 
+    /// Returns a compiler which hasn't compiled anything yet, for testing code generation of
+    /// individual operations.
+    fn test_compiler() -> TraceCompiler<u8> {
+        TraceCompiler::<u8> {
+            asm: dynasmrt::x64::Assembler::new().unwrap(),
+            register_content_map: HashMap::new(),
+            variable_location_map: HashMap::new(),
+            trace_inputs_local: None,
+            local_decls: HashMap::default(),
+            stack_builder: StackBuilder::default(),
+            addr_map: HashMap::new(),
+            _pd: PhantomData,
+        }
+    }
+
+    // Calls which can't be compiled yet must be rejected.
+    #[test]
+    fn unsupported_calls() {
+        let mut tc = test_compiler();
+        let abi = CallAbi {
+            args: vec![AbiClass::Memory],
+            ret: None,
+        };
+        // An argument passed in memory.
+        let target = CallOperand::Fn(String::from("abs"));
+        match tc.c_call(&target, &Vec::new(), &None, &abi) {
+            Err(CompileError::Unsupported(_)) => (),
+            _ => panic!("expected an Unsupported error"),
+        }
+        // An indirect call.
+        let target = CallOperand::FnPtr(Place::from(Local(1)));
+        let abi = CallAbi {
+            args: Vec::new(),
+            ret: None,
+        };
+        match tc.c_call(&target, &Vec::new(), &None, &abi) {
+            Err(CompileError::Unsupported(_)) => (),
+            _ => panic!("expected an Unsupported error"),
+        }
+    }
+
+    // A 16-byte struct is passed in two registers, which `c_call` can't do yet, so it must not be
+    // passed as only its first 8 bytes.
+    #[test]
+    fn c_call_struct_arg() {
+        // Like `struct S(u64, u64)`.
+        let u64_ty = Ty::UnsignedInt(UnsignedIntTy::U64);
+        let sty = Ty::Struct(StructTy {
+            fields: Fields {
+                offsets: vec![0, 8],
+                tys: vec![(0, 0), (0, 0)],
+            },
+            size_align: SizeAndAlign { align: 8, size: 16 },
+        });
+        let abi = CallAbi {
+            args: vec![sty.abi_class(|_| Some(&u64_ty))],
+            ret: None,
+        };
+        let target = CallOperand::Fn(String::from("abs"));
+        let args = vec![Operand::from(Local(1))];
+        match test_compiler().c_call(&target, &args, &None, &abi) {
+            Err(CompileError::Unsupported(_)) => (),
+            _ => panic!("expected an Unsupported error"),
+        }
+    }
+
     // A trace which contains a call to something which we don't have SIR for should emit a TIR
     // call operation.
     #[test]
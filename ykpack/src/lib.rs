@@ -20,14 +20,26 @@
 //!
 //!  The version field is automatically written and checked by the `Encoder` and `Decoder`
 //!  respectively.
+//!
+//! Without the (default) `std` feature, only the types are available, which then need only
+//! `core` and `alloc`. The `Encoder` and `Decoder` require `std`.
 
 #![feature(yk)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
 
+#[cfg(feature = "std")]
 mod decode;
+#[cfg(feature = "std")]
 mod encode;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 mod types;
 
+#[cfg(feature = "std")]
 pub use decode::Decoder;
+#[cfg(feature = "std")]
 pub use encode::Encoder;
 pub use types::*;
 
@@ -37,11 +49,12 @@ pub const BLOCK_LABEL_PREFIX: &str = "__YK_BLK";
 // ELF sections with this prefix contain SIR.
 pub const SIR_SECTION_PREFIX: &str = ".yksir_";
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::{
-        BasicBlock, BinOp, Body, Constant, ConstantInt, Decoder, Encoder, Local, LocalDecl,
-        Operand, Pack, Place, Rvalue, Statement, Terminator, UnsignedInt,
+        BasicBlock, BinOp, Body, Constant, ConstantFloat, ConstantInt, Decoder, Encoder, FloatTy,
+        Local, LocalDecl, Operand, Pack, Place, Rvalue, Statement, Terminator, Ty, Types,
+        UnsignedInt,
     };
     use fallible_iterator::{self, FallibleIterator};
     use std::io::{Cursor, Seek, SeekFrom};
@@ -132,6 +145,81 @@ mod tests {
         }
     }
 
+    // Serialise `packs` and then deserialise them again.
+    fn round_trip(packs: &[Pack]) -> Vec<Pack> {
+        let mut curs = get_curs();
+        let mut enc = Encoder::from(&mut curs);
+        for pack in packs {
+            enc.serialise(pack.clone()).unwrap();
+        }
+        enc.done().unwrap();
+
+        rewind_curs(&mut curs);
+        Decoder::from(&mut curs).collect::<Vec<_>>().unwrap()
+    }
+
+    // Check that floating point types and constants round trip exactly.
+    #[test]
+    fn test_floats() {
+        let assign_const = |l, cf| {
+            Statement::Assign(
+                Place::from(Local(l)),
+                Rvalue::Use(Operand::Constant(Constant::Float(cf))),
+            )
+        };
+        let stmts = vec![
+            assign_const(0, ConstantFloat::from(1.25f32)),
+            assign_const(1, ConstantFloat::from(-0.0f64)),
+            assign_const(2, ConstantFloat::from(f64::NAN)),
+            assign_const(3, ConstantFloat::from(f32::MIN_POSITIVE)),
+        ];
+        let inputs = vec![
+            Pack::Types(Types {
+                crate_hash: 1,
+                types: vec![Ty::Float(FloatTy::F32), Ty::Float(FloatTy::F64)],
+                thread_tracers: Vec::new(),
+            }),
+            Pack::Body(Body {
+                symbol_name: String::from("floats"),
+                blocks: vec![BasicBlock::new(stmts, Terminator::Return)],
+                flags: 0,
+                trace_inputs_local: None,
+                local_decls: Vec::new(),
+            }),
+        ];
+        assert_eq!(round_trip(&inputs), inputs);
+    }
+
+    // Check that the char type and char constants round trip.
+    #[test]
+    fn test_chars() {
+        let stmts = ['a', '\0', '\u{10ffff}']
+            .iter()
+            .zip(0..)
+            .map(|(c, l)| {
+                Statement::Assign(
+                    Place::from(Local(l)),
+                    Rvalue::Use(Operand::Constant(Constant::Char(*c))),
+                )
+            })
+            .collect();
+        let inputs = vec![
+            Pack::Types(Types {
+                crate_hash: 1,
+                types: vec![Ty::Char],
+                thread_tracers: Vec::new(),
+            }),
+            Pack::Body(Body {
+                symbol_name: String::from("chars"),
+                blocks: vec![BasicBlock::new(stmts, Terminator::Return)],
+                flags: 0,
+                trace_inputs_local: None,
+                local_decls: vec![LocalDecl { ty: (1, 0) }; 3],
+            }),
+        ];
+        assert_eq!(round_trip(&inputs), inputs);
+    }
+
     // FIXME convert this test to using fm.
     #[test]
     fn test_text_dump() {
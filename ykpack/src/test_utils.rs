@@ -0,0 +1,152 @@
+//! Helpers for building SIR fixtures in tests.
+
+use crate::types::{ArrayTy, Fields, SizeAndAlign, StructTy, TupleTy, Ty, TyIndex, TypeId, Types};
+use std::{collections::HashMap, convert::TryFrom};
+
+/// Builds a `Types` table for a made up crate. Types are given names when they are added, so that
+/// tests can refer to them when building aggregates, or when building local declarations.
+pub struct TypesBuilder {
+    types: Types,
+    names: HashMap<String, TyIndex>,
+}
+
+impl TypesBuilder {
+    /// Create a builder for the types of the crate with hash `crate_hash`.
+    pub fn new(crate_hash: u64) -> Self {
+        Self {
+            types: Types {
+                crate_hash,
+                types: Vec::new(),
+                thread_tracers: Vec::new(),
+            },
+            names: HashMap::new(),
+        }
+    }
+
+    /// Adds the type `ty` under the name `name`, returning its ID.
+    pub fn add(&mut self, name: &str, ty: Ty) -> TypeId {
+        let idx = TyIndex::try_from(self.types.types.len()).unwrap();
+        if self.names.insert(String::from(name), idx).is_some() {
+            panic!("type {} added twice", name);
+        }
+        self.types.types.push(ty);
+        (self.types.crate_hash, idx)
+    }
+
+    /// Returns the ID of the type previously added under the name `name`.
+    pub fn id(&self, name: &str) -> TypeId {
+        match self.names.get(name) {
+            Some(idx) => (self.types.crate_hash, *idx),
+            None => panic!("no type named {}", name),
+        }
+    }
+
+    /// Adds a tuple type whose fields have the named types, returning its ID.
+    pub fn add_tuple(&mut self, name: &str, fields: &[&str]) -> TypeId {
+        let (fields, size_align) = self.layout(fields);
+        self.add(name, Ty::Tuple(TupleTy { fields, size_align }))
+    }
+
+    /// Adds a struct type whose fields have the named types, returning its ID. Fields are laid out
+    /// in the order given (i.e. as if the struct were `#[repr(C)]`).
+    pub fn add_struct(&mut self, name: &str, fields: &[&str]) -> TypeId {
+        let (fields, size_align) = self.layout(fields);
+        self.add(name, Ty::Struct(StructTy { fields, size_align }))
+    }
+
+    /// Adds an array type of `len` elements of the named type, returning its ID.
+    pub fn add_array(&mut self, name: &str, elem: &str, len: u64) -> TypeId {
+        let elem = self.id(elem);
+        let elem_ty = &self.types.types[usize::try_from(elem.1).unwrap()];
+        let aty = ArrayTy::new(elem, elem_ty, len);
+        self.add(name, Ty::Array(aty))
+    }
+
+    /// Marks the named type as being a thread tracer.
+    pub fn thread_tracer(&mut self, name: &str) {
+        let idx = self.id(name).1;
+        self.types.thread_tracers.push(idx);
+    }
+
+    /// Consumes the builder, returning the types added to it.
+    pub fn build(self) -> Types {
+        self.types
+    }
+
+    /// Lays out fields of the named types in order, inserting padding as required by alignment.
+    fn layout(&self, names: &[&str]) -> (Fields, SizeAndAlign) {
+        let align_up = |off: u64, align: u64| (off + align - 1) / align * align;
+        let mut offsets = Vec::new();
+        let mut tys = Vec::new();
+        let mut off = 0;
+        let mut align = 1;
+        for name in names {
+            let tyid = self.id(name);
+            let ty = &self.types.types[usize::try_from(tyid.1).unwrap()];
+            off = align_up(off, ty.align());
+            offsets.push(off);
+            tys.push(tyid);
+            off += ty.size();
+            align = align.max(ty.align());
+        }
+        let size_align = SizeAndAlign {
+            align: i32::try_from(align).unwrap(),
+            size: i32::try_from(align_up(off, align)).unwrap(),
+        };
+        (Fields { offsets, tys }, size_align)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TypesBuilder;
+    use crate::types::{Fields, SizeAndAlign, TupleTy, Ty, UnsignedIntTy};
+
+    #[test]
+    fn tuple_layout() {
+        let mut tb = TypesBuilder::new(42);
+        let u8_id = tb.add("u8", Ty::UnsignedInt(UnsignedIntTy::U8));
+        let u64_id = tb.add("u64", Ty::UnsignedInt(UnsignedIntTy::U64));
+        let tup_id = tb.add_tuple("(u8, u64)", &["u8", "u64"]);
+        assert_eq!(tup_id, (42, 2));
+        assert_eq!(tb.id("u64"), u64_id);
+
+        let types = tb.build();
+        assert_eq!(types.crate_hash, 42);
+        assert_eq!(
+            types.types[2],
+            Ty::Tuple(TupleTy {
+                fields: Fields {
+                    offsets: vec![0, 8],
+                    tys: vec![u8_id, u64_id],
+                },
+                size_align: SizeAndAlign { align: 8, size: 16 },
+            })
+        );
+    }
+
+    #[test]
+    fn struct_trailing_padding() {
+        let mut tb = TypesBuilder::new(0);
+        tb.add("u32", Ty::UnsignedInt(UnsignedIntTy::U32));
+        tb.add("u8", Ty::UnsignedInt(UnsignedIntTy::U8));
+        tb.add_struct("S", &["u32", "u8"]);
+        tb.thread_tracer("S");
+        let types = tb.build();
+        assert_eq!(types.types[2].size(), 8);
+        assert_eq!(types.types[2].align(), 4);
+        assert_eq!(types.thread_tracers, vec![2]);
+    }
+
+    #[test]
+    fn array_of_tuples() {
+        let mut tb = TypesBuilder::new(0);
+        tb.add("u16", Ty::UnsignedInt(UnsignedIntTy::U16));
+        tb.add("u8", Ty::UnsignedInt(UnsignedIntTy::U8));
+        tb.add_tuple("(u16, u8)", &["u16", "u8"]);
+        tb.add_array("[(u16, u8); 4]", "(u16, u8)", 4);
+        let types = tb.build();
+        assert_eq!(types.types[3].size(), 16);
+        assert_eq!(types.types[3].align(), 2);
+    }
+}
@@ -1,11 +1,19 @@
 //! Types for the Yorick intermediate language.
 
-use serde::{Deserialize, Serialize};
-use std::{
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
     convert::TryFrom,
     fmt::{self, Display},
     mem,
 };
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "std")]
+use std::error::Error;
 
 pub type CrateHash = u64;
 pub type DefIndex = u32;
@@ -33,6 +41,21 @@ pub enum Ty {
     Bool,
     /// Anything that we've not yet defined a lowering for.
     Unimplemented(String),
+    // Variants are serialised by index, so new ones must be added below to keep SIR emitted by
+    // older compilers decodable.
+    /// A fixed-length array type.
+    Array(ArrayTy),
+    /// An enum type.
+    Enum(EnumTy),
+    /// Floating point numbers.
+    Float(FloatTy),
+    /// A Unicode scalar value.
+    Char,
+    /// A reference to a dynamically sized slice (including `str`), which is a pointer and a
+    /// length. The type ID is that of the slice's elements (`u8` for `str`).
+    FatRef(TypeId),
+    /// A function pointer.
+    FnPtr,
 }
 
 impl Display for Ty {
@@ -40,16 +63,78 @@ impl Display for Ty {
         match self {
             Ty::SignedInt(si) => write!(f, "{}", si),
             Ty::UnsignedInt(ui) => write!(f, "{}", ui),
+            Ty::Float(fty) => write!(f, "{}", fty),
             Ty::Struct(sty) => write!(f, "{}", sty),
             Ty::Tuple(tty) => write!(f, "{}", tty),
+            Ty::Array(aty) => write!(f, "{}", aty),
+            Ty::Enum(ety) => write!(f, "{}", ety),
             Ty::Ref(rty) => write!(f, "&{:?}", rty),
+            Ty::FatRef(elem) => write!(f, "&[{:?}]", elem),
+            Ty::FnPtr => write!(f, "fn ptr"),
             Ty::Bool => write!(f, "bool"),
+            Ty::Char => write!(f, "char"),
             Ty::Unimplemented(m) => write!(f, "Unimplemented: {}", m),
         }
     }
 }
 
+/// Displays a type, resolving the types which it refers to through a `Types` table. Returned by
+/// `Ty::display_with()`.
+pub struct TyDisplay<'a> {
+    ty: &'a Ty,
+    types: &'a Types,
+    /// The IDs of the types being printed which (transitively) refer to `ty`.
+    visited: Vec<TypeId>,
+}
+
+impl Display for TyDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Type IDs from other crates, or which are out of range, are printed raw. So are those
+        // we are already printing, otherwise a type which refers to itself would never finish.
+        let resolve = |id: &TypeId| -> Option<TyDisplay<'_>> {
+            if id.0 != self.types.crate_hash || self.visited.contains(id) {
+                return None;
+            }
+            let ty = self.types.types.get(usize::try_from(id.1).unwrap())?;
+            let mut visited = self.visited.clone();
+            visited.push(*id);
+            Some(TyDisplay {
+                ty,
+                types: self.types,
+                visited,
+            })
+        };
+        match self.ty {
+            Ty::Ref(rty) => match resolve(rty) {
+                Some(r) => write!(f, "&{}", r),
+                None => write!(f, "{}", self.ty),
+            },
+            Ty::FatRef(elem) => match resolve(elem) {
+                Some(e) => write!(f, "&[{}]", e),
+                None => write!(f, "{}", self.ty),
+            },
+            Ty::Array(aty) => match resolve(&aty.elem) {
+                Some(e) => write!(f, "[{}; {}], {}", e, aty.len, aty.size_align),
+                None => write!(f, "{}", self.ty),
+            },
+            // Aggregates are printed raw, as they may be recursive (e.g. a linked list node).
+            _ => write!(f, "{}", self.ty),
+        }
+    }
+}
+
 impl Ty {
+    /// Returns a displayable form of this type, which prints the types that references and arrays
+    /// refer to (e.g. `&i32`), rather than their type IDs (e.g. `&(1234, 7)`), if they are in
+    /// `types`. Aggregates are printed as for `Display`.
+    pub fn display_with<'a>(&'a self, types: &'a Types) -> impl Display + 'a {
+        TyDisplay {
+            ty: self,
+            types,
+            visited: Vec::new(),
+        }
+    }
+
     pub fn size(&self) -> u64 {
         match self {
             Ty::UnsignedInt(ui) => match ui {
@@ -68,51 +153,140 @@ impl Ty {
                 SignedIntTy::Isize => u64::try_from(mem::size_of::<isize>()).unwrap(),
                 SignedIntTy::I128 => 16,
             },
+            Ty::Float(fty) => match fty {
+                FloatTy::F32 => 4,
+                FloatTy::F64 => 8,
+            },
             Ty::Struct(sty) => u64::try_from(sty.size_align.size).unwrap(),
             Ty::Tuple(tty) => u64::try_from(tty.size_align.size).unwrap(),
-            Ty::Ref(_) => u64::try_from(mem::size_of::<usize>()).unwrap(),
+            Ty::Array(aty) => u64::try_from(aty.size_align.size).unwrap(),
+            Ty::Enum(ety) => u64::try_from(ety.size_align.size).unwrap(),
+            Ty::Ref(_) | Ty::FnPtr => u64::try_from(mem::size_of::<usize>()).unwrap(),
+            Ty::FatRef(_) => u64::try_from(mem::size_of::<usize>() * 2).unwrap(),
             Ty::Bool => u64::try_from(mem::size_of::<bool>()).unwrap(),
+            Ty::Char => 4,
             _ => todo!("{:?}", self),
         }
     }
 
+    /// Returns the alignment of the type, in bytes. The alignment of primitive types is that of
+    /// the target, which isn't necessarily the same as their size (e.g. `u64` on 32-bit x86).
     pub fn align(&self) -> u64 {
+        fn align_of<T>() -> u64 {
+            u64::try_from(mem::align_of::<T>()).unwrap()
+        }
+
         match self {
             Ty::UnsignedInt(ui) => match ui {
-                UnsignedIntTy::U8 => 1,
-                UnsignedIntTy::U16 => 2,
-                UnsignedIntTy::U32 => 4,
-                UnsignedIntTy::U64 => 8,
-                UnsignedIntTy::Usize =>
-                {
-                    #[cfg(target_arch = "x86_64")]
-                    8
-                }
-                UnsignedIntTy::U128 => 16,
+                UnsignedIntTy::U8 => align_of::<u8>(),
+                UnsignedIntTy::U16 => align_of::<u16>(),
+                UnsignedIntTy::U32 => align_of::<u32>(),
+                UnsignedIntTy::U64 => align_of::<u64>(),
+                UnsignedIntTy::Usize => align_of::<usize>(),
+                UnsignedIntTy::U128 => align_of::<u128>(),
             },
             Ty::SignedInt(ui) => match ui {
-                SignedIntTy::I8 => 1,
-                SignedIntTy::I16 => 2,
-                SignedIntTy::I32 => 4,
-                SignedIntTy::I64 => 8,
-                SignedIntTy::Isize =>
-                {
-                    #[cfg(target_arch = "x86_64")]
-                    8
-                }
-                SignedIntTy::I128 => 16,
+                SignedIntTy::I8 => align_of::<i8>(),
+                SignedIntTy::I16 => align_of::<i16>(),
+                SignedIntTy::I32 => align_of::<i32>(),
+                SignedIntTy::I64 => align_of::<i64>(),
+                SignedIntTy::Isize => align_of::<isize>(),
+                SignedIntTy::I128 => align_of::<i128>(),
+            },
+            Ty::Float(fty) => match fty {
+                FloatTy::F32 => align_of::<f32>(),
+                FloatTy::F64 => align_of::<f64>(),
             },
             Ty::Struct(sty) => u64::try_from(sty.size_align.align).unwrap(),
             Ty::Tuple(tty) => u64::try_from(tty.size_align.align).unwrap(),
-            Ty::Ref(_) =>
-            {
-                #[cfg(target_arch = "x86_64")]
-                8
-            }
-            Ty::Bool => u64::try_from(mem::size_of::<bool>()).unwrap(),
+            Ty::Array(aty) => u64::try_from(aty.size_align.align).unwrap(),
+            Ty::Enum(ety) => u64::try_from(ety.size_align.align).unwrap(),
+            Ty::Ref(_) | Ty::FatRef(_) | Ty::FnPtr => align_of::<*const u8>(),
+            Ty::Bool => align_of::<bool>(),
+            Ty::Char => align_of::<char>(),
             _ => todo!("{:?}", self),
         }
     }
+
+    /// Returns the System V x86_64 ABI class of this type, which decides how a value of the type is
+    /// passed to, or returned from, a native call. `ty` looks up the types of aggregate fields.
+    ///
+    /// FIXME: The ABI passes values of up to two eightbytes in registers, classing each eightbyte
+    /// separately. Native calls can only pass one register per value so far, so anything bigger
+    /// than an eightbyte (e.g. a fat reference or a `u128`) is classed as memory. So are aggregates
+    /// containing floating point fields, whose eightbyte may be SSE or integer.
+    pub fn abi_class<'t, T>(&self, ty: T) -> AbiClass
+    where
+        T: Fn(&TypeId) -> Option<&'t Ty>,
+    {
+        match self {
+            Ty::Unimplemented(_) => AbiClass::Memory,
+            _ if self.size() > 8 => AbiClass::Memory,
+            _ => self.eightbyte_class(&ty),
+        }
+    }
+
+    /// Returns the ABI class of the single eightbyte holding this type, which is the merged class
+    /// of the fields for an aggregate. `ty` is as for `abi_class()`.
+    fn eightbyte_class<'t>(&self, ty: &dyn Fn(&TypeId) -> Option<&'t Ty>) -> AbiClass {
+        let fields: Vec<&TypeId> = match self {
+            Ty::SignedInt(_)
+            | Ty::UnsignedInt(_)
+            | Ty::Bool
+            | Ty::Char
+            | Ty::Ref(_)
+            | Ty::FnPtr => return AbiClass::Integer,
+            Ty::Float(_) => return AbiClass::Sse,
+            Ty::FatRef(_) | Ty::Unimplemented(_) => return AbiClass::Memory,
+            Ty::Struct(StructTy { fields, .. }) | Ty::Tuple(TupleTy { fields, .. }) => {
+                fields.tys.iter().collect()
+            }
+            Ty::Array(ArrayTy { elem, .. }) => vec![elem],
+            Ty::Enum(EnumTy {
+                discr_ty, variants, ..
+            }) => core::iter::once(discr_ty)
+                .chain(variants.iter().flat_map(|v| v.tys.iter()))
+                .collect(),
+        };
+        // Fields whose type can't be found might be floats, so we must assume the worst.
+        let int_field =
+            |t: &&TypeId| ty(t).map_or(false, |fty| fty.eightbyte_class(ty) == AbiClass::Integer);
+        if fields.iter().all(int_field) {
+            AbiClass::Integer
+        } else {
+            AbiClass::Memory
+        }
+    }
+
+    /// Replaces each `TypeId` referenced by this type with `f(type_id)`.
+    fn map_type_ids<F>(&mut self, mut f: F)
+    where
+        F: FnMut(TypeId) -> TypeId,
+    {
+        match self {
+            Ty::Struct(StructTy { fields, .. }) | Ty::Tuple(TupleTy { fields, .. }) => {
+                for t in fields.tys.iter_mut() {
+                    *t = f(*t);
+                }
+            }
+            Ty::Enum(EnumTy {
+                discr_ty, variants, ..
+            }) => {
+                *discr_ty = f(*discr_ty);
+                for t in variants.iter_mut().flat_map(|v| v.tys.iter_mut()) {
+                    *t = f(*t);
+                }
+            }
+            Ty::Ref(t) | Ty::FatRef(t) | Ty::Array(ArrayTy { elem: t, .. }) => *t = f(*t),
+            Ty::SignedInt(_)
+            | Ty::UnsignedInt(_)
+            | Ty::Float(_)
+            | Ty::Bool
+            | Ty::Char
+            | Ty::FnPtr
+            | Ty::Unimplemented(_) => (),
+        }
+    }
 }
 
 /// Describes the various signed integer types.
@@ -165,6 +339,23 @@ impl Display for UnsignedIntTy {
     }
 }
 
+/// Describes the various floating point types.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
+pub enum FloatTy {
+    F32,
+    F64,
+}
+
+impl Display for FloatTy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::F32 => "f32",
+            Self::F64 => "f64",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub struct Fields {
     /// Field offsets.
@@ -234,11 +425,75 @@ impl Display for StructTy {
     }
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
+pub struct ArrayTy {
+    /// The type of the array's elements.
+    pub elem: TypeId,
+    /// The number of elements.
+    pub len: u64,
+    /// The size and alignment of the whole array. Since a `Ty` can't look up the type of its
+    /// elements by itself, this is computed up-front by `ArrayTy::new()`.
+    pub size_align: SizeAndAlign,
+}
+
+impl ArrayTy {
+    /// Makes an array of `len` elements of type `elem_ty`, whose ID is `elem`.
+    pub fn new(elem: TypeId, elem_ty: &Ty, len: u64) -> Self {
+        // A Rust type's size is always a multiple of its alignment, so there's no padding
+        // between elements.
+        let size_align = SizeAndAlign {
+            align: i32::try_from(elem_ty.align()).unwrap(),
+            size: i32::try_from(elem_ty.size().checked_mul(len).unwrap()).unwrap(),
+        };
+        Self {
+            elem,
+            len,
+            size_align,
+        }
+    }
+}
+
+impl Display for ArrayTy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:?}; {}], {}", self.elem, self.len, self.size_align)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
+pub struct EnumTy {
+    /// The type of the discriminant.
+    pub discr_ty: TypeId,
+    /// The offset of the discriminant, in bytes.
+    pub discr_offset: u64,
+    /// The fields of each variant, indexed by variant index. Offsets are from the start of the
+    /// enum, as variants share storage.
+    pub variants: Vec<Fields>,
+    /// The size and alignment of the enum.
+    pub size_align: SizeAndAlign,
+}
+
+impl Display for EnumTy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "EnumTy {{ discr: {:?} at {}, variants: [{}], {} }}",
+            self.discr_ty,
+            self.discr_offset,
+            self.variants
+                .iter()
+                .map(|v| format!("{{ {} }}", v))
+                .collect::<Vec<String>>()
+                .join(", "),
+            self.size_align
+        )
+    }
+}
+
 /// rmp-serde serialisable 128-bit numeric types, to work around:
 /// https://github.com/3Hren/msgpack-rust/issues/169
 macro_rules! new_ser128 {
     ($n: ident, $t: ty) => {
-        #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+        #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
         pub struct $n {
             hi: u64,
             lo: u64,
@@ -284,12 +539,76 @@ pub struct Place {
 }
 
 impl Place {
+    /// Returns the type of this place, found by starting from the type of the place's local and
+    /// walking the projection. `local_decl` looks up the declaration of a local, and `ty` looks up
+    /// a type by its ID.
+    pub fn resolved_ty<'d, 't, D, T>(&self, local_decl: D, ty: T) -> Result<TypeId, PlaceError>
+    where
+        D: Fn(&Local) -> Option<&'d LocalDecl>,
+        T: Fn(&TypeId) -> Option<&'t Ty>,
+    {
+        let mut tyid = local_decl(&self.local)
+            .ok_or(PlaceError::NoLocalDecl(self.local))?
+            .ty;
+        for proj in &self.projection {
+            let cur = ty(&tyid).ok_or(PlaceError::UnknownType(tyid))?;
+            tyid = match (proj, cur) {
+                (Projection::Field(idx), Ty::Struct(StructTy { fields, .. }))
+                | (Projection::Field(idx), Ty::Tuple(TupleTy { fields, .. })) => *fields
+                    .tys
+                    .get(usize::try_from(*idx).unwrap())
+                    .ok_or(PlaceError::BadField(tyid, *idx))?,
+                (Projection::Field(idx), _) => return Err(PlaceError::BadField(tyid, *idx)),
+                (Projection::Deref, Ty::Ref(inner)) => *inner,
+                (Projection::Deref, _) => return Err(PlaceError::BadDeref(tyid)),
+                (Projection::Index(_), Ty::Array(ArrayTy { elem, .. }))
+                | (Projection::ConstantIndex { .. }, Ty::Array(ArrayTy { elem, .. })) => *elem,
+                (Projection::Index(_), _) | (Projection::ConstantIndex { .. }, _) => {
+                    return Err(PlaceError::BadIndex(tyid))
+                }
+                (Projection::Unimplemented(s), _) => {
+                    return Err(PlaceError::Unimplemented(s.clone()))
+                }
+            };
+        }
+        Ok(tyid)
+    }
+
+    /// Returns the size of this place in bytes. This is the size of the type found by walking the
+    /// projection, e.g. the size of a single field, not of the whole local. `local_decl` and `ty`
+    /// are as for `resolved_ty()`.
+    pub fn size<'d, 't, D, T>(&self, local_decl: D, ty: T) -> Result<u64, PlaceError>
+    where
+        D: Fn(&Local) -> Option<&'d LocalDecl>,
+        T: Fn(&TypeId) -> Option<&'t Ty>,
+    {
+        let tyid = self.resolved_ty(local_decl, &ty)?;
+        Ok(ty(&tyid).ok_or(PlaceError::UnknownType(tyid))?.size())
+    }
+
     fn push_maybe_defined_locals(&self, locals: &mut Vec<Local>) {
         locals.push(self.local);
     }
 
+    /// Pushes the place's local, and any locals used to index it, onto `locals`. Even when the
+    /// place is being assigned to, its index locals are only used.
     fn push_used_locals(&self, locals: &mut Vec<Local>) {
         locals.push(self.local);
+        for p in &self.projection {
+            if let Projection::Index(l) = p {
+                locals.push(*l);
+            }
+        }
+    }
+
+    /// Replaces each local referenced by this place (including index locals) with `f(local)`.
+    pub fn map_locals<F: FnMut(Local) -> Local>(&mut self, f: &mut F) {
+        self.local = f(self.local);
+        for p in &mut self.projection {
+            if let Projection::Index(l) = p {
+                *l = f(*l);
+            }
+        }
     }
 }
 
@@ -315,6 +634,39 @@ impl Display for Place {
     }
 }
 
+/// Reasons why the type of a place couldn't be resolved.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PlaceError {
+    /// There's no declaration for the place's local.
+    NoLocalDecl(Local),
+    /// The type with the specified ID couldn't be found.
+    UnknownType(TypeId),
+    /// A field projection was applied to a type without the specified field.
+    BadField(TypeId, FieldIndex),
+    /// A dereference was applied to a type which isn't a reference.
+    BadDeref(TypeId),
+    /// An index was applied to a type which isn't an array.
+    BadIndex(TypeId),
+    /// The place contains a projection we can't handle yet.
+    Unimplemented(String),
+}
+
+impl Display for PlaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoLocalDecl(l) => write!(f, "No declaration for local {}", l),
+            Self::UnknownType(t) => write!(f, "Unknown type: {:?}", t),
+            Self::BadField(t, idx) => write!(f, "Type {:?} has no field {}", t, idx),
+            Self::BadDeref(t) => write!(f, "Can't dereference type {:?}", t),
+            Self::BadIndex(t) => write!(f, "Can't index type {:?}", t),
+            Self::Unimplemented(s) => write!(f, "Unimplemented projection: {}", s),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for PlaceError {}
+
 impl From<Local> for Place {
     fn from(local: Local) -> Self {
         Self {
@@ -344,6 +696,16 @@ pub enum Projection {
     Field(FieldIndex),
     Deref,
     Unimplemented(String),
+    // Added after `Unimplemented`, so that the serialised indices of the older variants are kept.
+    /// Indexes an array with the value of a local.
+    Index(Local),
+    /// Indexes an array with a constant. If `from_end` is true, the index counts back from the
+    /// end, so that `offset` 1 is the last element. The array has at least `min_length` elements.
+    ConstantIndex {
+        offset: u64,
+        min_length: u64,
+        from_end: bool,
+    },
 }
 
 impl Display for Projection {
@@ -351,11 +713,38 @@ impl Display for Projection {
         match self {
             Self::Field(idx) => write!(f, ".{}", idx),
             Self::Deref => write!(f, ""),
+            Self::Index(l) => write!(f, "[{}]", l),
+            Self::ConstantIndex {
+                offset,
+                min_length,
+                from_end,
+            } => {
+                let sign = if *from_end { "-" } else { "" };
+                write!(f, "[{}{} of {}]", sign, offset, min_length)
+            }
             Self::Unimplemented(s) => write!(f, ".(unimplemented projection: {:?})", s),
         }
     }
 }
 
+/// Reasons that a pack may be malformed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PackValidationError {
+    /// A body's trace inputs local has no declaration.
+    BadInputsLocal(Local),
+}
+
+impl Display for PackValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadInputsLocal(l) => write!(f, "Trace inputs local {} is not declared", l),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for PackValidationError {}
+
 /// Bits in the `flags` bitfield in `Body`.
 pub mod bodyflags {
     pub const TRACE_HEAD: u8 = 1;
@@ -386,6 +775,123 @@ pub struct Body {
     pub local_decls: Vec<LocalDecl>,
 }
 
+impl Body {
+    /// Returns the block with index `idx`, or `None` if there's no such block.
+    pub fn block(&self, idx: BasicBlockIndex) -> Option<&BasicBlock> {
+        self.blocks.get(usize::try_from(idx).unwrap())
+    }
+
+    /// Checks that the body is well-formed, so that it's safe to index `local_decls` with the
+    /// locals it references.
+    pub fn validate(&self) -> Result<(), PackValidationError> {
+        if let Some(til) = self.trace_inputs_local {
+            if usize::try_from(til.0).unwrap() >= self.local_decls.len() {
+                return Err(PackValidationError::BadInputsLocal(til));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes blocks which are unreachable from the entry block (block 0), renumbering the
+    /// remaining blocks and rewriting the targets of all terminators accordingly. The relative
+    /// order of the surviving blocks is preserved.
+    pub fn prune_unreachable(&mut self) {
+        if self.blocks.is_empty() {
+            return;
+        }
+
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut todo = vec![0];
+        while let Some(bb) = todo.pop() {
+            let bb_usize = usize::try_from(bb).unwrap();
+            if reachable[bb_usize] {
+                continue;
+            }
+            reachable[bb_usize] = true;
+            todo.extend(self.blocks[bb_usize].term.successors());
+        }
+
+        // Maps old block indices to new ones. Unreachable blocks have no mapping.
+        let mut remap = Vec::with_capacity(self.blocks.len());
+        let mut next_idx = 0;
+        for r in &reachable {
+            if *r {
+                remap.push(Some(next_idx));
+                next_idx += 1;
+            } else {
+                remap.push(None);
+            }
+        }
+
+        let old_blocks = mem::take(&mut self.blocks);
+        for (bb, blk) in old_blocks.into_iter().enumerate() {
+            if reachable[bb] {
+                self.blocks.push(blk);
+            }
+        }
+        for blk in &mut self.blocks {
+            blk.term
+                .map_successors(|old| remap[usize::try_from(old).unwrap()].unwrap());
+        }
+    }
+
+    /// Returns true if the two bodies behave the same, ignoring their symbol names and the
+    /// numbering of their blocks. Only the blocks reachable from the entry block (block 0) are
+    /// compared, so a body is semantically equal to itself after `prune_unreachable()`.
+    pub fn semantically_eq(&self, other: &Body) -> bool {
+        if self.flags != other.flags
+            || self.trace_inputs_local != other.trace_inputs_local
+            || self.local_decls != other.local_decls
+        {
+            return false;
+        }
+        if self.blocks.is_empty() || other.blocks.is_empty() {
+            return self.blocks.is_empty() && other.blocks.is_empty();
+        }
+
+        // Walk both bodies from their entry blocks in lockstep, building a one-to-one mapping
+        // between their block indices.
+        let mut map: Vec<Option<BasicBlockIndex>> = vec![None; self.blocks.len()];
+        let mut mapped = vec![false; other.blocks.len()];
+        let mut todo = vec![(0, 0)];
+        while let Some((a, b)) = todo.pop() {
+            let (a_usize, b_usize) = (usize::try_from(a).unwrap(), usize::try_from(b).unwrap());
+            match map[a_usize] {
+                Some(prev) if prev == b => continue,
+                Some(_) => return false,
+                None if mapped[b_usize] => return false,
+                None => (),
+            }
+            map[a_usize] = Some(b);
+            mapped[b_usize] = true;
+
+            let (a_blk, b_blk) = (&self.blocks[a_usize], &other.blocks[b_usize]);
+            if a_blk.stmts != b_blk.stmts {
+                return false;
+            }
+            // Compare the terminators with their successors blanked out, then pair up the
+            // successors.
+            let (mut a_term, mut b_term) = (a_blk.term.clone(), b_blk.term.clone());
+            a_term.map_successors(|_| 0);
+            b_term.map_successors(|_| 0);
+            if a_term != b_term {
+                return false;
+            }
+            let (a_succs, b_succs) = (a_blk.term.successors(), b_blk.term.successors());
+            for (a_succ, b_succ) in a_succs.into_iter().zip(b_succs) {
+                if usize::try_from(a_succ).unwrap() >= self.blocks.len()
+                    || usize::try_from(b_succ).unwrap() >= other.blocks.len()
+                {
+                    // A malformed body doesn't behave like anything.
+                    return false;
+                }
+                todo.push((a_succ, b_succ));
+            }
+        }
+        true
+    }
+}
+
 impl Display for Body {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "symbol: {}", self.symbol_name)?;
@@ -428,7 +934,7 @@ impl Display for BasicBlock {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub enum Statement {
     /// Do nothing.
     Nop,
@@ -443,7 +949,7 @@ pub enum Statement {
     StorageDead(Local),
     /// A (non-inlined) call from a TIR trace to a binary symbol using the system ABI. This does
     /// not appear in SIR.
-    Call(CallOperand, Vec<Operand>, Option<Place>),
+    Call(CallOperand, Vec<Operand>, Option<Place>, CallAbi),
     /// Any unimplemented lowering maps to this variant.
     /// The string inside is the stringified MIR statement.
     Unimplemented(String),
@@ -455,59 +961,109 @@ impl Statement {
     /// into the local (there is no explicit liveness marker in SIR/TIR).
     pub fn maybe_defined_locals(&self) -> Vec<Local> {
         let mut ret = Vec::new();
+        self.maybe_defined_locals_into(&mut ret);
+        ret
+    }
 
+    /// Like `maybe_defined_locals()`, but pushes the locals onto `out` instead of allocating a
+    /// new vector. Existing elements of `out` are left untouched.
+    pub fn maybe_defined_locals_into(&self, out: &mut Vec<Local>) {
         match self {
             Statement::Nop => (),
-            Statement::Assign(place, _rval) => place.push_maybe_defined_locals(&mut ret),
+            Statement::Assign(place, _rval) => place.push_maybe_defined_locals(out),
             // `Enter` doesn't define the destination, as that will be defined by an inlined assignment.
             Statement::Enter(_target, args, _dest_place, start_idx) => {
                 for idx in 0..args.len() {
                     // + 1 to skip return value.
-                    ret.push(Local(start_idx + u32::try_from(idx).unwrap() + 1));
+                    out.push(Local(start_idx + u32::try_from(idx).unwrap() + 1));
                 }
             }
             Statement::Leave => (),
             Statement::StorageDead(_) => (),
-            Statement::Call(_target, _args, dest) => {
+            Statement::Call(_target, _args, dest, _abi) => {
                 if let Some(dest) = dest {
-                    dest.push_maybe_defined_locals(&mut ret);
+                    dest.push_maybe_defined_locals(out);
                 }
             }
             Statement::Unimplemented(_) => (),
         }
-        ret
     }
 
     /// Returns a vector of locals that this SIR statement uses but does not define.
     pub fn used_locals(&self) -> Vec<Local> {
         let mut ret = Vec::new();
+        self.used_locals_into(&mut ret);
+        ret
+    }
 
+    /// Like `used_locals()`, but pushes the locals onto `out` instead of allocating a new vector.
+    /// Existing elements of `out` are left untouched.
+    pub fn used_locals_into(&self, out: &mut Vec<Local>) {
         match self {
             Statement::Nop => (),
             Statement::Assign(place, rval) => {
-                rval.push_used_locals(&mut ret);
-                place.push_used_locals(&mut ret);
+                rval.push_used_locals(out);
+                place.push_used_locals(out);
             }
             // `Enter` doesn't use the callee args. Inlined statements will use them instead.
             Statement::Enter(_target, _args, _opt_place, _idx) => (),
             Statement::Leave => (),
             Statement::StorageDead(_) => (),
-            Statement::Call(_target, args, _dest) => {
+            Statement::Call(target, args, _dest, _abi) => {
+                target.push_used_locals(out);
                 for a in args {
-                    a.push_used_locals(&mut ret);
+                    a.push_used_locals(out);
                 }
             }
             Statement::Unimplemented(_) => (),
         }
-        ret
+    }
+
+    /// Returns true if this statement is an assignment of a place to itself (e.g. `$1 = $1`),
+    /// which has no effect and can be dropped.
+    pub fn is_self_assign(&self) -> bool {
+        match self {
+            Statement::Assign(dest, Rvalue::Use(Operand::Place(src))) => dest == src,
+            _ => false,
+        }
+    }
+
+    /// Replaces each local explicitly referenced by this statement with `f(local)`. The locals
+    /// which an `Enter` implicitly defines (see `maybe_defined_locals()`) are left alone, as they
+    /// are derived from its offset.
+    pub fn map_locals<F: FnMut(Local) -> Local>(&mut self, f: &mut F) {
+        match self {
+            Statement::Nop | Statement::Leave | Statement::Unimplemented(_) => (),
+            Statement::Assign(place, rval) => {
+                place.map_locals(f);
+                rval.map_locals(f);
+            }
+            Statement::Enter(target, args, dest, _) | Statement::Call(target, args, dest, _) => {
+                target.map_locals(f);
+                for a in args {
+                    a.map_locals(f);
+                }
+                if let Some(dest) = dest {
+                    dest.map_locals(f);
+                }
+            }
+            Statement::StorageDead(local) => *local = f(*local),
+        }
     }
 
     /// Returns a vector of locals either used or defined by this statement.
     pub fn referenced_locals(&self) -> Vec<Local> {
-        let mut ret = self.maybe_defined_locals();
-        ret.extend(self.used_locals());
+        let mut ret = Vec::new();
+        self.referenced_locals_into(&mut ret);
         ret
     }
+
+    /// Like `referenced_locals()`, but pushes the locals onto `out` instead of allocating a new
+    /// vector. Existing elements of `out` are left untouched.
+    pub fn referenced_locals_into(&self, out: &mut Vec<Local>) {
+        self.maybe_defined_locals_into(out);
+        self.used_locals_into(out);
+    }
 }
 
 impl Display for Statement {
@@ -530,7 +1086,7 @@ impl Display for Statement {
             }
             Statement::Leave => write!(f, "leave"),
             Statement::StorageDead(local) => write!(f, "dead({})", local),
-            Statement::Call(op, args, dest) => {
+            Statement::Call(op, args, dest, _abi) => {
                 let args_s = args
                     .iter()
                     .map(|a| format!("{}", a))
@@ -548,8 +1104,39 @@ impl Display for Statement {
     }
 }
 
+/// The System V x86_64 ABI classes. These decide how values are passed to and returned from
+/// native calls.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum AbiClass {
+    /// Passed in general purpose registers, if enough are available.
+    Integer,
+    /// Passed in vector registers, if enough are available.
+    Sse,
+    /// Passed on the stack. Returned via a pointer supplied by the caller.
+    Memory,
+}
+
+impl Display for AbiClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Integer => write!(f, "integer"),
+            Self::Sse => write!(f, "sse"),
+            Self::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+/// How the arguments and return value of a native call are passed.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
+pub struct CallAbi {
+    /// The class of each argument.
+    pub args: Vec<AbiClass>,
+    /// The class of the return value, if there is one.
+    pub ret: Option<AbiClass>,
+}
+
 /// The right-hand side of an assignment.
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub enum Rvalue {
     Use(Operand),
     BinaryOp(BinOp, Operand, Operand),
@@ -574,6 +1161,19 @@ impl Rvalue {
             Rvalue::Unimplemented(_) => (),
         }
     }
+
+    /// Replaces each local referenced by this rvalue with `f(local)`.
+    pub fn map_locals<F: FnMut(Local) -> Local>(&mut self, f: &mut F) {
+        match self {
+            Rvalue::Use(opnd) => opnd.map_locals(f),
+            Rvalue::BinaryOp(_op, opnd1, opnd2) | Rvalue::CheckedBinaryOp(_op, opnd1, opnd2) => {
+                opnd1.map_locals(f);
+                opnd2.map_locals(f);
+            }
+            Rvalue::Ref(plc) => plc.map_locals(f),
+            Rvalue::Unimplemented(_) => (),
+        }
+    }
 }
 
 impl Display for Rvalue {
@@ -597,19 +1197,49 @@ impl From<Local> for Rvalue {
 }
 
 /// Unlike in MIR, we don't track move/copy semantics in operands.
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub enum Operand {
     Place(Place),
     Constant(Constant),
 }
 
 impl Operand {
+    /// Returns the ABI class of this operand when passed to a native call. `local_decl` and `ty`
+    /// are as for `Place::resolved_ty()`.
+    pub fn abi_class<'d, 't, D, T>(&self, local_decl: D, ty: T) -> Result<AbiClass, PlaceError>
+    where
+        D: Fn(&Local) -> Option<&'d LocalDecl>,
+        T: Fn(&TypeId) -> Option<&'t Ty>,
+    {
+        match self {
+            Operand::Place(p) => {
+                let tyid = p.resolved_ty(local_decl, &ty)?;
+                Ok(ty(&tyid)
+                    .ok_or(PlaceError::UnknownType(tyid))?
+                    .abi_class(&ty))
+            }
+            Operand::Constant(Constant::Int(_))
+            | Operand::Constant(Constant::Bool(_))
+            | Operand::Constant(Constant::Char(_)) => Ok(AbiClass::Integer),
+            Operand::Constant(Constant::Float(_)) => Ok(AbiClass::Sse),
+            Operand::Constant(Constant::Unimplemented(_)) => Ok(AbiClass::Memory),
+        }
+    }
+
     fn push_used_locals(&self, locals: &mut Vec<Local>) {
         match self {
             Operand::Place(plc) => plc.push_used_locals(locals),
             Operand::Constant(_) => (),
         }
     }
+
+    /// Replaces each local referenced by this operand with `f(local)`.
+    pub fn map_locals<F: FnMut(Local) -> Local>(&mut self, f: &mut F) {
+        match self {
+            Operand::Place(plc) => plc.map_locals(f),
+            Operand::Constant(_) => (),
+        }
+    }
 }
 
 impl Display for Operand {
@@ -633,19 +1263,40 @@ impl From<Place> for Operand {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub enum Constant {
     Int(ConstantInt),
     Bool(bool),
     Unimplemented(String),
+    // Added after `Unimplemented`, so that the serialised indices of the older variants are kept.
+    Float(ConstantFloat),
+    Char(char),
 }
 
 impl Constant {
-    pub fn i64_cast(&self) -> i64 {
+    /// Returns the value of the constant as an `i64`, as if it were loaded into a general purpose
+    /// register. Returns `None` for floats, which live in vector registers instead, and for
+    /// unimplemented constants, whose values are unknown.
+    pub fn i64_cast(&self) -> Option<i64> {
         match self {
-            Self::Int(ci) => ci.i64_cast(),
-            Self::Bool(b) => *b as i64,
-            Self::Unimplemented(_) => unreachable!(),
+            Self::Int(ci) => Some(ci.i64_cast()),
+            Self::Bool(b) => Some(*b as i64),
+            Self::Char(c) => Some(i64::from(u32::from(*c))),
+            Self::Float(_) | Self::Unimplemented(_) => None,
+        }
+    }
+
+    /// Returns true if the two constants have the same value. Unlike `==`, integers of different
+    /// types compare equal if they are numerically equal (e.g. `1u8` and `1usize`). Likewise for
+    /// floats, which also follow IEEE 754 comparison (e.g. `-0.0 == 0.0`, but `NaN != NaN`).
+    /// Unimplemented constants are never equal to anything, as their values are unknown.
+    pub fn value_eq(&self, other: &Constant) -> bool {
+        match (self, other) {
+            (Self::Int(a), Self::Int(b)) => a.value_eq(b),
+            (Self::Float(a), Self::Float(b)) => a.f64_val() == b.f64_val(),
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::Char(a), Self::Char(b)) => a == b,
+            _ => false,
         }
     }
 }
@@ -654,13 +1305,62 @@ impl Display for Constant {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Constant::Int(i) => write!(f, "{}", i),
+            Constant::Float(fl) => write!(f, "{}", fl),
             Constant::Bool(b) => write!(f, "{}", b),
+            Constant::Char(c) => write!(f, "{:?}", c),
             Constant::Unimplemented(s) => write!(f, "unimplemented constant: {:?}", s),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+/// A floating point constant. The value is stored as its IEEE 754 bit pattern, so that constants
+/// are serialised exactly, and so that `==` and hashing are well-defined (even for NaNs).
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
+pub enum ConstantFloat {
+    F32(u32),
+    F64(u64),
+}
+
+impl From<f32> for ConstantFloat {
+    fn from(v: f32) -> Self {
+        ConstantFloat::F32(v.to_bits())
+    }
+}
+
+impl From<f64> for ConstantFloat {
+    fn from(v: f64) -> Self {
+        ConstantFloat::F64(v.to_bits())
+    }
+}
+
+impl ConstantFloat {
+    /// Returns the type of the constant.
+    pub fn ty(&self) -> FloatTy {
+        match self {
+            ConstantFloat::F32(_) => FloatTy::F32,
+            ConstantFloat::F64(_) => FloatTy::F64,
+        }
+    }
+
+    /// Returns the value of the constant. `f32` constants are widened, which is exact.
+    pub fn f64_val(&self) -> f64 {
+        match self {
+            ConstantFloat::F32(bits) => f64::from(f32::from_bits(*bits)),
+            ConstantFloat::F64(bits) => f64::from_bits(*bits),
+        }
+    }
+}
+
+impl Display for ConstantFloat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConstantFloat::F32(bits) => write!(f, "{:?}f32", f32::from_bits(*bits)),
+            ConstantFloat::F64(bits) => write!(f, "{:?}f64", f64::from_bits(*bits)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub enum ConstantInt {
     UnsignedInt(UnsignedInt),
     SignedInt(SignedInt),
@@ -677,6 +1377,70 @@ impl From<bool> for ConstantInt {
 }
 
 impl ConstantInt {
+    /// Returns the width of the constant's type, in bytes.
+    pub fn width_bytes(&self) -> usize {
+        match self {
+            ConstantInt::UnsignedInt(ui) => match ui {
+                UnsignedInt::U8(_) => 1,
+                UnsignedInt::U16(_) => 2,
+                UnsignedInt::U32(_) => 4,
+                UnsignedInt::U64(_) => 8,
+                UnsignedInt::Usize(_) => mem::size_of::<usize>(),
+                UnsignedInt::U128(_) => 16,
+            },
+            ConstantInt::SignedInt(si) => match si {
+                SignedInt::I8(_) => 1,
+                SignedInt::I16(_) => 2,
+                SignedInt::I32(_) => 4,
+                SignedInt::I64(_) => 8,
+                SignedInt::Isize(_) => mem::size_of::<isize>(),
+                SignedInt::I128(_) => 16,
+            },
+        }
+    }
+
+    /// Returns true if the constant's type is a signed integer type.
+    pub fn is_signed(&self) -> bool {
+        match self {
+            ConstantInt::UnsignedInt(_) => false,
+            ConstantInt::SignedInt(_) => true,
+        }
+    }
+
+    /// Returns true if the two integer constants are numerically equal, regardless of their types.
+    pub fn value_eq(&self, other: &ConstantInt) -> bool {
+        self.wide_val() == other.wide_val()
+    }
+
+    /// Returns the constant's value as a sign flag and a sign-extended 128-bit pattern. Every value
+    /// of every integer type has a distinct representation.
+    fn wide_val(&self) -> (bool, u128) {
+        match self {
+            ConstantInt::UnsignedInt(ui) => {
+                let v = match ui {
+                    UnsignedInt::U8(i) => u128::from(*i),
+                    UnsignedInt::U16(i) => u128::from(*i),
+                    UnsignedInt::U32(i) => u128::from(*i),
+                    UnsignedInt::U64(i) => u128::from(*i),
+                    UnsignedInt::Usize(i) => *i as u128,
+                    UnsignedInt::U128(i) => i.val(),
+                };
+                (false, v)
+            }
+            ConstantInt::SignedInt(si) => {
+                let v = match si {
+                    SignedInt::I8(i) => i128::from(*i),
+                    SignedInt::I16(i) => i128::from(*i),
+                    SignedInt::I32(i) => i128::from(*i),
+                    SignedInt::I64(i) => i128::from(*i),
+                    SignedInt::Isize(i) => *i as i128,
+                    SignedInt::I128(i) => i.val(),
+                };
+                (v < 0, v as u128)
+            }
+        }
+    }
+
     /// Returns an i64 value suitable for loading into a register.
     /// If the constant is signed, then it will be sign-extended.
     pub fn i64_cast(&self) -> i64 {
@@ -735,6 +1499,74 @@ impl ConstantInt {
     }
 }
 
+/// The error returned when converting a `ConstantInt` into a primitive integer type of a different
+/// width or signedness.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConstantIntTypeError;
+
+impl Display for ConstantIntTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "constant integer has the wrong type")
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for ConstantIntTypeError {}
+
+/// Generate conversions between a primitive integer type and ConstantInt.
+/// The second form is for 128-bit integers, which are wrapped in SerU128/SerI128.
+macro_rules! const_int_conversions {
+    ($rs_t: ident, $yk_t: ident, $yk_variant: ident) => {
+        impl From<$rs_t> for ConstantInt {
+            fn from(v: $rs_t) -> Self {
+                ConstantInt::$yk_t($yk_t::$yk_variant(v))
+            }
+        }
+
+        impl TryFrom<ConstantInt> for $rs_t {
+            type Error = ConstantIntTypeError;
+
+            fn try_from(c: ConstantInt) -> Result<Self, Self::Error> {
+                match c {
+                    ConstantInt::$yk_t($yk_t::$yk_variant(v)) => Ok(v),
+                    _ => Err(ConstantIntTypeError),
+                }
+            }
+        }
+    };
+    ($rs_t: ident, $yk_t: ident, $yk_variant: ident, $ser_t: ident) => {
+        impl From<$rs_t> for ConstantInt {
+            fn from(v: $rs_t) -> Self {
+                ConstantInt::$yk_t($yk_t::$yk_variant($ser_t::new(v)))
+            }
+        }
+
+        impl TryFrom<ConstantInt> for $rs_t {
+            type Error = ConstantIntTypeError;
+
+            fn try_from(c: ConstantInt) -> Result<Self, Self::Error> {
+                match c {
+                    ConstantInt::$yk_t($yk_t::$yk_variant(v)) => Ok(v.val()),
+                    _ => Err(ConstantIntTypeError),
+                }
+            }
+        }
+    };
+}
+
+const_int_conversions!(u8, UnsignedInt, U8);
+const_int_conversions!(u16, UnsignedInt, U16);
+const_int_conversions!(u32, UnsignedInt, U32);
+const_int_conversions!(u64, UnsignedInt, U64);
+const_int_conversions!(usize, UnsignedInt, Usize);
+const_int_conversions!(u128, UnsignedInt, U128, SerU128);
+const_int_conversions!(i8, SignedInt, I8);
+const_int_conversions!(i16, SignedInt, I16);
+const_int_conversions!(i32, SignedInt, I32);
+const_int_conversions!(i64, SignedInt, I64);
+const_int_conversions!(isize, SignedInt, Isize);
+const_int_conversions!(i128, SignedInt, I128, SerI128);
+
 impl Display for ConstantInt {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -744,7 +1576,7 @@ impl Display for ConstantInt {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub enum UnsignedInt {
     Usize(usize),
     U8(u8),
@@ -767,7 +1599,7 @@ impl Display for UnsignedInt {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub enum SignedInt {
     Isize(isize),
     I8(i8),
@@ -791,12 +1623,15 @@ impl Display for SignedInt {
 }
 
 /// A call target.
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub enum CallOperand {
     /// A call to a binary symbol by name.
     Fn(String),
     /// An unknown or unhandled callable.
     Unknown, // FIXME -- Find out what else. Closures jump to mind.
+    /// An indirect call through the function pointer stored in a place. The target isn't known
+    /// until run-time: to make the call, the address must be loaded from the place.
+    FnPtr(Place),
 }
 
 impl CallOperand {
@@ -807,12 +1642,26 @@ impl CallOperand {
             None
         }
     }
+
+    fn push_used_locals(&self, locals: &mut Vec<Local>) {
+        if let Self::FnPtr(place) = self {
+            place.push_used_locals(locals);
+        }
+    }
+
+    /// Replaces each local referenced by this call target with `f(local)`.
+    pub fn map_locals<F: FnMut(Local) -> Local>(&mut self, f: &mut F) {
+        if let Self::FnPtr(place) = self {
+            place.map_locals(f);
+        }
+    }
 }
 
 impl Display for CallOperand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             CallOperand::Fn(sym_name) => write!(f, "{}", sym_name),
+            CallOperand::FnPtr(place) => write!(f, "fn_ptr({})", place),
             CallOperand::Unknown => write!(f, "<unknown>"),
         }
     }
@@ -855,6 +1704,66 @@ pub enum Terminator {
     Unimplemented(String), // FIXME will eventually disappear.
 }
 
+impl Terminator {
+    /// Returns the indices of the blocks that this terminator may transfer control to.
+    pub fn successors(&self) -> Vec<BasicBlockIndex> {
+        match self {
+            Terminator::Goto(bb) => vec![*bb],
+            Terminator::SwitchInt {
+                target_bbs,
+                otherwise_bb,
+                ..
+            } => {
+                let mut ret = target_bbs.clone();
+                ret.push(*otherwise_bb);
+                ret
+            }
+            Terminator::Return | Terminator::Unreachable | Terminator::Unimplemented(_) => {
+                Vec::new()
+            }
+            Terminator::Drop { target_bb, .. }
+            | Terminator::DropAndReplace { target_bb, .. }
+            | Terminator::Assert { target_bb, .. } => vec![*target_bb],
+            Terminator::Call { destination, .. } => {
+                if let Some((_, bb)) = destination {
+                    vec![*bb]
+                } else {
+                    Vec::new()
+                }
+            }
+        }
+    }
+
+    /// Replaces each successor block index `bb` of this terminator with `f(bb)`.
+    fn map_successors<F>(&mut self, f: F)
+    where
+        F: Fn(BasicBlockIndex) -> BasicBlockIndex,
+    {
+        match self {
+            Terminator::Goto(bb) => *bb = f(*bb),
+            Terminator::SwitchInt {
+                target_bbs,
+                otherwise_bb,
+                ..
+            } => {
+                for bb in target_bbs.iter_mut() {
+                    *bb = f(*bb);
+                }
+                *otherwise_bb = f(*otherwise_bb);
+            }
+            Terminator::Return | Terminator::Unreachable | Terminator::Unimplemented(_) => (),
+            Terminator::Drop { target_bb, .. }
+            | Terminator::DropAndReplace { target_bb, .. }
+            | Terminator::Assert { target_bb, .. } => *target_bb = f(*target_bb),
+            Terminator::Call { destination, .. } => {
+                if let Some((_, bb)) = destination {
+                    *bb = f(*bb);
+                }
+            }
+        }
+    }
+}
+
 impl Display for Terminator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -924,7 +1833,7 @@ impl Display for Terminator {
 }
 
 /// Binary operations.
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
 pub enum BinOp {
     Add,
     Sub,
@@ -945,6 +1854,31 @@ pub enum BinOp {
     Offset,
 }
 
+impl BinOp {
+    /// Returns true if the operator compares its operands, producing a boolean.
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            Self::Eq | Self::Lt | Self::Le | Self::Ne | Self::Ge | Self::Gt
+        )
+    }
+
+    /// Returns true if the operator can overflow, and thus may appear in a
+    /// `Rvalue::CheckedBinaryOp`. Division and remainder aren't included, as their overflow and
+    /// division-by-zero checks are separate `Assert` terminators.
+    pub fn is_checkable(&self) -> bool {
+        matches!(
+            self,
+            Self::Add | Self::Sub | Self::Mul | Self::Shl | Self::Shr
+        )
+    }
+
+    /// Returns true if the operator is a bit shift.
+    pub fn is_shift(&self) -> bool {
+        matches!(self, Self::Shl | Self::Shr)
+    }
+}
+
 impl Display for BinOp {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
@@ -996,9 +1930,149 @@ pub struct Types {
     pub thread_tracers: Vec<u32>,
 }
 
+/// Maps the type indices of a merged-in `Types` to their indices in the merged table.
+/// The element at index `i` is the new index of the incoming type `i`.
+pub type RemapTable = Vec<TyIndex>;
+
+impl Types {
+    /// Merges the types of `other` into `self`, deduplicating types which are structurally equal
+    /// to one already present. Returns a table mapping each incoming `TyIndex` to its index in
+    /// `self`, which can be used to rewrite the `TypeId`s of bodies from `other`'s crate.
+    ///
+    /// Type references inside `other` are rewritten to point into `self`. Incoming types are
+    /// merged after the types they refer to, so that the references can be compared. A type in a
+    /// reference cycle (e.g. a linked list node) refers to a type which hasn't been merged yet, and
+    /// is conservatively never deduplicated.
+    pub fn merge(&mut self, other: Types) -> RemapTable {
+        let self_hash = self.crate_hash;
+        let other_hash = other.crate_hash;
+        let mut remap: Vec<Option<TyIndex>> = vec![None; other.types.len()];
+        // Incoming types which were not deduplicated, paired with their new index.
+        let mut appended = Vec::new();
+        // The rewritten forms of the appended types which later incoming types may be
+        // deduplicated against, paired with their new index.
+        let mut canonical: Vec<(Ty, TyIndex)> = Vec::new();
+
+        for idx in Self::merge_order(&other.types, other_hash) {
+            let ty = &other.types[idx];
+            // Rewrite references to incoming types we already know the final index for.
+            let mut resolved = true;
+            let mut cand = ty.clone();
+            cand.map_type_ids(|tid| {
+                if tid.0 == other_hash {
+                    if let Some(new_idx) = remap[usize::try_from(tid.1).unwrap()] {
+                        return (self_hash, new_idx);
+                    }
+                    resolved = false;
+                }
+                tid
+            });
+
+            let existing = if resolved {
+                self.types
+                    .iter()
+                    .position(|t| t == &cand)
+                    .map(|e| TyIndex::try_from(e).unwrap())
+                    .or_else(|| canonical.iter().find(|(t, _)| t == &cand).map(|(_, e)| *e))
+            } else {
+                None
+            };
+            let new_idx = match existing {
+                Some(e) => e,
+                None => {
+                    let new_idx = TyIndex::try_from(self.types.len() + appended.len()).unwrap();
+                    appended.push((new_idx, ty.clone()));
+                    if resolved {
+                        canonical.push((cand, new_idx));
+                    }
+                    new_idx
+                }
+            };
+            remap[idx] = Some(new_idx);
+        }
+
+        // Now all indices are known, rewrite the references in the appended types.
+        let remap = remap
+            .into_iter()
+            .map(|i| i.unwrap())
+            .collect::<RemapTable>();
+        for (new_idx, mut ty) in appended {
+            debug_assert_eq!(usize::try_from(new_idx).unwrap(), self.types.len());
+            ty.map_type_ids(|tid| {
+                if tid.0 == other_hash {
+                    (self_hash, remap[usize::try_from(tid.1).unwrap()])
+                } else {
+                    tid
+                }
+            });
+            self.types.push(ty);
+        }
+
+        for tt in other.thread_tracers {
+            let new_tt = remap[usize::try_from(tt).unwrap()];
+            if !self.thread_tracers.contains(&new_tt) {
+                self.thread_tracers.push(new_tt);
+            }
+        }
+
+        remap
+    }
+
+    /// Returns the indices of `types`, the types of the crate with hash `crate_hash`, ordered so
+    /// that each type comes after the types it refers to, except where they form a cycle.
+    fn merge_order(types: &[Ty], crate_hash: u64) -> Vec<usize> {
+        // The indices of the types which `types[idx]` refers to, last first.
+        let referents = |idx: usize| {
+            let mut refs = Vec::new();
+            types[idx].clone().map_type_ids(|tid| {
+                if tid.0 == crate_hash {
+                    refs.push(usize::try_from(tid.1).unwrap());
+                }
+                tid
+            });
+            refs.reverse();
+            refs
+        };
+
+        let mut order = Vec::with_capacity(types.len());
+        let mut seen = vec![false; types.len()];
+        // A depth-first search. Each entry is a type and the referents which are still to visit.
+        let mut stack = Vec::new();
+        for root in 0..types.len() {
+            if seen[root] {
+                continue;
+            }
+            seen[root] = true;
+            stack.push((root, referents(root)));
+            while let Some((idx, pending)) = stack.last_mut() {
+                match pending.pop() {
+                    Some(r) => {
+                        if r < types.len() && !seen[r] {
+                            seen[r] = true;
+                            stack.push((r, referents(r)));
+                        }
+                    }
+                    None => {
+                        order.push(*idx);
+                        stack.pop();
+                    }
+                }
+            }
+        }
+        order
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{ConstantInt, SerI128, SerU128, SignedInt, UnsignedInt};
+    use super::{
+        AbiClass, ArrayTy, BasicBlock, BinOp, Body, CallAbi, CallOperand, Constant, ConstantFloat,
+        ConstantInt, ConstantIntTypeError, EnumTy, Fields, FloatTy, Local, LocalDecl, Operand,
+        PackValidationError, Place, PlaceError, Projection, Rvalue, SerI128, SerU128, SignedInt,
+        SignedIntTy, SizeAndAlign, Statement, StructTy, Terminator, TupleTy, Ty, TypeId, Types,
+        UnsignedInt, UnsignedIntTy,
+    };
+    use std::{convert::TryFrom, mem};
 
     #[test]
     fn seru128_round_trip() {
@@ -1042,4 +2116,819 @@ mod tests {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn prune_unreachable() {
+        // bb0 -> bb2 -> bb4, with bb1 and bb3 unreachable.
+        let blocks = vec![
+            BasicBlock::new(vec![Statement::Nop], Terminator::Goto(2)),
+            BasicBlock::new(vec![], Terminator::Goto(3)),
+            BasicBlock::new(
+                vec![],
+                Terminator::SwitchInt {
+                    discr: Place::from(Local(1)),
+                    values: vec![SerU128::new(0)],
+                    target_bbs: vec![4],
+                    otherwise_bb: 4,
+                },
+            ),
+            BasicBlock::new(vec![], Terminator::Unreachable),
+            BasicBlock::new(vec![], Terminator::Return),
+        ];
+        let mut body = Body {
+            symbol_name: String::from("prune"),
+            blocks,
+            flags: 0,
+            trace_inputs_local: None,
+            local_decls: Vec::new(),
+        };
+        body.prune_unreachable();
+
+        assert_eq!(body.blocks.len(), 3);
+        assert_eq!(body.blocks[0].term, Terminator::Goto(1));
+        assert_eq!(
+            body.blocks[1].term,
+            Terminator::SwitchInt {
+                discr: Place::from(Local(1)),
+                values: vec![SerU128::new(0)],
+                target_bbs: vec![2],
+                otherwise_bb: 2,
+            }
+        );
+        assert_eq!(body.blocks[2].term, Terminator::Return);
+    }
+
+    #[test]
+    fn semantically_eq() {
+        // bb0 -> bb2 -> {bb4, bb3}, with bb1 unreachable.
+        let blocks = vec![
+            BasicBlock::new(vec![Statement::Nop], Terminator::Goto(2)),
+            BasicBlock::new(vec![], Terminator::Goto(3)),
+            BasicBlock::new(
+                vec![],
+                Terminator::SwitchInt {
+                    discr: Place::from(Local(1)),
+                    values: vec![SerU128::new(0)],
+                    target_bbs: vec![4],
+                    otherwise_bb: 3,
+                },
+            ),
+            BasicBlock::new(vec![], Terminator::Unreachable),
+            BasicBlock::new(vec![], Terminator::Return),
+        ];
+        let orig = Body {
+            symbol_name: String::from("orig"),
+            blocks,
+            flags: 0,
+            trace_inputs_local: None,
+            local_decls: Vec::new(),
+        };
+
+        let mut pruned = orig.clone();
+        pruned.symbol_name = String::from("pruned");
+        pruned.prune_unreachable();
+        assert_ne!(orig, pruned);
+        assert!(orig.semantically_eq(&pruned));
+        assert!(pruned.semantically_eq(&orig));
+
+        // Swapping the switch's targets changes behaviour.
+        let mut swapped = pruned.clone();
+        swapped.blocks[1].term = Terminator::SwitchInt {
+            discr: Place::from(Local(1)),
+            values: vec![SerU128::new(0)],
+            target_bbs: vec![2],
+            otherwise_bb: 3,
+        };
+        assert!(!orig.semantically_eq(&swapped));
+
+        // As does changing a statement.
+        let mut changed = pruned.clone();
+        changed.blocks[0].stmts.clear();
+        assert!(!orig.semantically_eq(&changed));
+    }
+
+    #[test]
+    fn merge_types() {
+        let pair = |crate_hash, a, b| {
+            Ty::Tuple(TupleTy {
+                fields: Fields {
+                    offsets: vec![0, 8],
+                    tys: vec![(crate_hash, a), (crate_hash, b)],
+                },
+                size_align: SizeAndAlign { align: 8, size: 16 },
+            })
+        };
+
+        let mut tys1 = Types {
+            crate_hash: 1,
+            types: vec![
+                Ty::UnsignedInt(UnsignedIntTy::U8),
+                Ty::UnsignedInt(UnsignedIntTy::U64),
+                pair(1, 0, 1),
+            ],
+            thread_tracers: vec![],
+        };
+        let tys2 = Types {
+            crate_hash: 2,
+            types: vec![
+                Ty::UnsignedInt(UnsignedIntTy::U64),
+                Ty::Bool,
+                Ty::UnsignedInt(UnsignedIntTy::U8),
+                pair(2, 2, 0),
+                Ty::Ref((2, 1)),
+            ],
+            thread_tracers: vec![1],
+        };
+
+        let remap = tys1.merge(tys2);
+        assert_eq!(remap, vec![1, 3, 0, 2, 4]);
+        assert_eq!(
+            tys1.types,
+            vec![
+                Ty::UnsignedInt(UnsignedIntTy::U8),
+                Ty::UnsignedInt(UnsignedIntTy::U64),
+                pair(1, 0, 1),
+                Ty::Bool,
+                Ty::Ref((1, 3)),
+            ]
+        );
+        assert_eq!(tys1.thread_tracers, vec![3]);
+
+        // Types equal to other incoming types are deduplicated too, even if they come first and
+        // refer to types later in the table. Self-referential types are never deduplicated.
+        let tys3 = Types {
+            crate_hash: 3,
+            types: vec![
+                pair(3, 1, 2),
+                Ty::Float(FloatTy::F32),
+                Ty::Float(FloatTy::F32),
+                pair(3, 1, 1),
+                Ty::Ref((3, 4)),
+                Ty::Ref((3, 5)),
+            ],
+            thread_tracers: vec![],
+        };
+        let remap = tys1.merge(tys3);
+        assert_eq!(remap, vec![6, 5, 5, 6, 7, 8]);
+        assert_eq!(
+            tys1.types[5..],
+            [
+                Ty::Float(FloatTy::F32),
+                pair(1, 5, 5),
+                Ty::Ref((1, 7)),
+                Ty::Ref((1, 8)),
+            ]
+        );
+    }
+
+    #[test]
+    fn locals_into_matches_allocating() {
+        let stmts = vec![
+            Statement::Nop,
+            Statement::Assign(
+                Place::from(Local(1)),
+                Rvalue::BinaryOp(BinOp::Add, Operand::from(Local(2)), Operand::from(Local(3))),
+            ),
+            Statement::Enter(
+                CallOperand::Fn(String::from("f")),
+                vec![Operand::from(Local(1)), Operand::from(Local(2))],
+                Some(Place::from(Local(4))),
+                5,
+            ),
+            Statement::Leave,
+            Statement::StorageDead(Local(3)),
+            Statement::Call(
+                CallOperand::Fn(String::from("g")),
+                vec![Operand::from(Local(6))],
+                Some(Place::from(Local(7))),
+                CallAbi {
+                    args: vec![AbiClass::Integer],
+                    ret: Some(AbiClass::Integer),
+                },
+            ),
+        ];
+
+        // The buffer is reused across statements, so the `_into` versions must only append.
+        let mut buf = vec![Local(99)];
+        for stmt in &stmts {
+            buf.truncate(1);
+            stmt.maybe_defined_locals_into(&mut buf);
+            assert_eq!(buf[1..], stmt.maybe_defined_locals()[..]);
+            buf.truncate(1);
+            stmt.used_locals_into(&mut buf);
+            assert_eq!(buf[1..], stmt.used_locals()[..]);
+            buf.truncate(1);
+            stmt.referenced_locals_into(&mut buf);
+            assert_eq!(buf[1..], stmt.referenced_locals()[..]);
+            assert_eq!(buf[0], Local(99));
+        }
+    }
+
+    #[test]
+    fn self_assign() {
+        let p = Place::from(Local(1));
+        assert!(
+            Statement::Assign(p.clone(), Rvalue::Use(Operand::from(p.clone()))).is_self_assign()
+        );
+        assert!(
+            !Statement::Assign(p.clone(), Rvalue::Use(Operand::from(Local(2)))).is_self_assign()
+        );
+        assert!(!Statement::Assign(p.clone(), Rvalue::Ref(p.clone())).is_self_assign());
+        assert!(!Statement::Nop.is_self_assign());
+    }
+
+    #[test]
+    fn place_resolved_ty() {
+        // 0: u8, 1: (u8, u8), 2: &(u8, u8), 3: struct { &(u8, u8), u8 }.
+        let types = vec![
+            Ty::UnsignedInt(UnsignedIntTy::U8),
+            Ty::Tuple(TupleTy {
+                fields: Fields {
+                    offsets: vec![0, 1],
+                    tys: vec![(0, 0), (0, 0)],
+                },
+                size_align: SizeAndAlign { align: 1, size: 2 },
+            }),
+            Ty::Ref((0, 1)),
+            Ty::Struct(StructTy {
+                fields: Fields {
+                    offsets: vec![0, 8],
+                    tys: vec![(0, 2), (0, 0)],
+                },
+                size_align: SizeAndAlign { align: 8, size: 16 },
+            }),
+        ];
+        let decls = vec![LocalDecl { ty: (0, 3) }];
+        let resolve = |projection: Vec<Projection>| {
+            Place {
+                local: Local(0),
+                projection,
+            }
+            .resolved_ty(
+                |l| decls.get(usize::try_from(l.0).unwrap()),
+                |t| types.get(usize::try_from(t.1).unwrap()),
+            )
+        };
+
+        assert_eq!(resolve(vec![]), Ok((0, 3)));
+        // Field.
+        assert_eq!(resolve(vec![Projection::Field(1)]), Ok((0, 0)));
+        assert_eq!(resolve(vec![Projection::Field(0)]), Ok((0, 2)));
+        // Deref.
+        assert_eq!(
+            resolve(vec![Projection::Field(0), Projection::Deref]),
+            Ok((0, 1))
+        );
+        // Nested.
+        assert_eq!(
+            resolve(vec![
+                Projection::Field(0),
+                Projection::Deref,
+                Projection::Field(1)
+            ]),
+            Ok((0, 0))
+        );
+
+        // Errors.
+        assert_eq!(
+            resolve(vec![Projection::Deref]),
+            Err(PlaceError::BadDeref((0, 3)))
+        );
+        assert_eq!(
+            resolve(vec![Projection::Field(2)]),
+            Err(PlaceError::BadField((0, 3), 2))
+        );
+        assert_eq!(
+            resolve(vec![Projection::Field(1), Projection::Field(0)]),
+            Err(PlaceError::BadField((0, 0), 0))
+        );
+        assert_eq!(
+            Place::from(Local(1)).resolved_ty(|_| None, |_| None),
+            Err(PlaceError::NoLocalDecl(Local(1)))
+        );
+    }
+
+    #[test]
+    fn place_size() {
+        // 0: u8, 1: (u8, u64).
+        let types = vec![
+            Ty::UnsignedInt(UnsignedIntTy::U8),
+            Ty::UnsignedInt(UnsignedIntTy::U64),
+            Ty::Tuple(TupleTy {
+                fields: Fields {
+                    offsets: vec![0, 8],
+                    tys: vec![(0, 0), (0, 1)],
+                },
+                size_align: SizeAndAlign { align: 8, size: 16 },
+            }),
+        ];
+        let decls = vec![LocalDecl { ty: (0, 2) }];
+        let size = |projection: Vec<Projection>| {
+            Place {
+                local: Local(0),
+                projection,
+            }
+            .size(
+                |l| decls.get(usize::try_from(l.0).unwrap()),
+                |t| types.get(usize::try_from(t.1).unwrap()),
+            )
+        };
+
+        assert_eq!(size(vec![]), Ok(16));
+        assert_eq!(size(vec![Projection::Field(0)]), Ok(1));
+        assert_eq!(size(vec![Projection::Field(1)]), Ok(8));
+        assert_eq!(
+            size(vec![Projection::Deref]),
+            Err(PlaceError::BadDeref((0, 2)))
+        );
+    }
+
+    #[test]
+    fn const_int_primitive_round_trip() {
+        macro_rules! round_trip {
+            ($($v: expr),*) => {
+                $(assert_eq!(TryFrom::try_from(ConstantInt::from($v)), Ok($v));)*
+            };
+        }
+        round_trip!(
+            u8::MAX,
+            u16::MAX - 1,
+            u32::MAX - 2,
+            u64::MAX - 3,
+            usize::MAX - 4,
+            u128::MAX - 5,
+            i8::MIN,
+            i16::MIN + 1,
+            i32::MIN + 2,
+            i64::MIN + 3,
+            isize::MIN + 4,
+            i128::MIN + 5
+        );
+
+        // Width and sign mismatches are rejected.
+        assert_eq!(
+            u16::try_from(ConstantInt::from(1u8)),
+            Err(ConstantIntTypeError)
+        );
+        assert_eq!(
+            i8::try_from(ConstantInt::from(1u8)),
+            Err(ConstantIntTypeError)
+        );
+        assert_eq!(
+            u64::try_from(ConstantInt::from(1usize)),
+            Err(ConstantIntTypeError)
+        );
+        assert_eq!(
+            i128::try_from(ConstantInt::from(1u128)),
+            Err(ConstantIntTypeError)
+        );
+    }
+
+    #[test]
+    fn validate_inputs_local() {
+        let mut body = Body {
+            symbol_name: String::from("f"),
+            blocks: vec![BasicBlock::new(vec![], Terminator::Return)],
+            flags: 0,
+            trace_inputs_local: Some(Local(1)),
+            local_decls: vec![LocalDecl { ty: (0, 0) }, LocalDecl { ty: (0, 1) }],
+        };
+        assert_eq!(body.validate(), Ok(()));
+
+        body.trace_inputs_local = None;
+        assert_eq!(body.validate(), Ok(()));
+
+        body.trace_inputs_local = Some(Local(2));
+        assert_eq!(
+            body.validate(),
+            Err(PackValidationError::BadInputsLocal(Local(2)))
+        );
+    }
+
+    #[test]
+    fn body_block() {
+        let body = Body {
+            symbol_name: String::from("f"),
+            blocks: vec![
+                BasicBlock::new(vec![], Terminator::Goto(1)),
+                BasicBlock::new(vec![], Terminator::Return),
+            ],
+            flags: 0,
+            trace_inputs_local: None,
+            local_decls: vec![],
+        };
+        assert_eq!(body.block(1).unwrap().term, Terminator::Return);
+        assert!(body.block(2).is_none());
+        assert!(body.block(u32::MAX).is_none());
+    }
+
+    #[test]
+    fn const_int_width_and_sign() {
+        let cases = vec![
+            (ConstantInt::from(0u8), 1, false),
+            (ConstantInt::from(0u16), 2, false),
+            (ConstantInt::from(0u32), 4, false),
+            (ConstantInt::from(0u64), 8, false),
+            (
+                ConstantInt::from(0usize),
+                std::mem::size_of::<usize>(),
+                false,
+            ),
+            (ConstantInt::from(0u128), 16, false),
+            (ConstantInt::from(0i8), 1, true),
+            (ConstantInt::from(0i16), 2, true),
+            (ConstantInt::from(0i32), 4, true),
+            (ConstantInt::from(0i64), 8, true),
+            (
+                ConstantInt::from(0isize),
+                std::mem::size_of::<isize>(),
+                true,
+            ),
+            (ConstantInt::from(0i128), 16, true),
+        ];
+        for (cst, width, signed) in cases {
+            assert_eq!(cst.width_bytes(), width, "{}", cst);
+            assert_eq!(cst.is_signed(), signed, "{}", cst);
+        }
+    }
+
+    #[test]
+    fn binop_classification() {
+        // (op, is_comparison, is_checkable, is_shift)
+        let cases = vec![
+            (BinOp::Add, false, true, false),
+            (BinOp::Sub, false, true, false),
+            (BinOp::Mul, false, true, false),
+            (BinOp::Div, false, false, false),
+            (BinOp::Rem, false, false, false),
+            (BinOp::BitXor, false, false, false),
+            (BinOp::BitAnd, false, false, false),
+            (BinOp::BitOr, false, false, false),
+            (BinOp::Shl, false, true, true),
+            (BinOp::Shr, false, true, true),
+            (BinOp::Eq, true, false, false),
+            (BinOp::Lt, true, false, false),
+            (BinOp::Le, true, false, false),
+            (BinOp::Ne, true, false, false),
+            (BinOp::Ge, true, false, false),
+            (BinOp::Gt, true, false, false),
+            (BinOp::Offset, false, false, false),
+        ];
+        for (op, cmp, chk, shift) in cases {
+            assert_eq!(op.is_comparison(), cmp, "{}", op);
+            assert_eq!(op.is_checkable(), chk, "{}", op);
+            assert_eq!(op.is_shift(), shift, "{}", op);
+        }
+    }
+
+    #[test]
+    fn rvalue_hash() {
+        use std::collections::HashMap;
+
+        let add = |c: u128| {
+            Rvalue::BinaryOp(
+                BinOp::Add,
+                Operand::from(Local(1)),
+                Operand::Constant(Constant::Int(ConstantInt::UnsignedInt(UnsignedInt::U128(
+                    SerU128::new(c),
+                )))),
+            )
+        };
+        let mut map = HashMap::new();
+        map.insert(add(1), 1);
+        map.insert(add(1 << 64), 2);
+        assert_eq!(map.insert(add(1), 3), Some(1));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&add(1 << 64)], 2);
+        assert!(!map.contains_key(&Rvalue::Use(Operand::from(Local(1)))));
+    }
+
+    #[test]
+    fn constant_value_eq() {
+        let int = Constant::Int;
+        let one_u8 = int(ConstantInt::from(1u8));
+        let one_usize = int(ConstantInt::from(1usize));
+        let one_i32 = int(ConstantInt::from(1i32));
+        assert_ne!(one_u8, one_usize);
+        assert!(one_u8.value_eq(&one_usize));
+        assert!(one_u8.value_eq(&one_i32));
+        assert!(!one_u8.value_eq(&int(ConstantInt::from(2u8))));
+
+        // Negative values never equal unsigned ones, even with the same bit pattern.
+        let neg_one = int(ConstantInt::from(-1i8));
+        assert!(neg_one.value_eq(&int(ConstantInt::from(-1i128))));
+        assert!(!neg_one.value_eq(&int(ConstantInt::from(std::u8::MAX))));
+        assert!(!neg_one.value_eq(&int(ConstantInt::from(std::u128::MAX))));
+
+        assert!(Constant::Bool(true).value_eq(&Constant::Bool(true)));
+        assert!(!Constant::Bool(true).value_eq(&one_u8));
+        let unimpl = Constant::Unimplemented(String::from("x"));
+        assert_eq!(unimpl, unimpl.clone());
+        assert!(!unimpl.value_eq(&unimpl));
+    }
+
+    #[test]
+    fn array_size_align() {
+        let u32_ty = Ty::UnsignedInt(UnsignedIntTy::U32);
+        let arr = Ty::Array(ArrayTy::new((0, 0), &u32_ty, 5));
+        assert_eq!(arr.size(), 20);
+        assert_eq!(arr.align(), 4);
+        assert_eq!(arr.abi_class(|_| Some(&u32_ty)), AbiClass::Memory);
+
+        // An array of arrays.
+        let arr2 = Ty::Array(ArrayTy::new((0, 1), &arr, 3));
+        assert_eq!(arr2.size(), 60);
+        assert_eq!(arr2.align(), 4);
+
+        let empty = Ty::Array(ArrayTy::new((0, 0), &u32_ty, 0));
+        assert_eq!(empty.size(), 0);
+        assert_eq!(empty.align(), 4);
+        assert_eq!(empty.abi_class(|_| Some(&u32_ty)), AbiClass::Integer);
+    }
+
+    #[test]
+    fn array_display() {
+        let arr = Ty::Array(ArrayTy::new((7, 1), &Ty::Bool, 3));
+        assert_eq!(arr.to_string(), "[(7, 1); 3], align: 1, size: 3");
+    }
+
+    #[test]
+    fn enum_size_align() {
+        // Like `Option<u32>`: a `u8` discriminant, then the payload of `Some`.
+        let ety = Ty::Enum(EnumTy {
+            discr_ty: (0, 0),
+            discr_offset: 0,
+            variants: vec![
+                Fields {
+                    offsets: Vec::new(),
+                    tys: Vec::new(),
+                },
+                Fields {
+                    offsets: vec![4],
+                    tys: vec![(0, 1)],
+                },
+            ],
+            size_align: SizeAndAlign { align: 4, size: 8 },
+        });
+        assert_eq!(ety.size(), 8);
+        assert_eq!(ety.align(), 4);
+        assert_eq!(
+            ety.to_string(),
+            "EnumTy { discr: (0, 0) at 0, variants: [{ offsets: [], tys: [] }, \
+             { offsets: [4], tys: [(0, 1)] }], align: 4, size: 8 }"
+        );
+    }
+
+    #[test]
+    fn float_types() {
+        let f32_ty = Ty::Float(FloatTy::F32);
+        let f64_ty = Ty::Float(FloatTy::F64);
+        assert_eq!((f32_ty.size(), f32_ty.align()), (4, 4));
+        assert_eq!((f64_ty.size(), f64_ty.align()), (8, 8));
+        assert_eq!(f64_ty.abi_class(|_| None), AbiClass::Sse);
+        assert_eq!(f32_ty.to_string(), "f32");
+        assert_eq!(f64_ty.to_string(), "f64");
+    }
+
+    #[test]
+    fn float_constants() {
+        let fl = Constant::Float;
+        assert_eq!(fl(ConstantFloat::from(1.5f32)).to_string(), "1.5f32");
+        assert_eq!(fl(ConstantFloat::from(-2.0f64)).to_string(), "-2.0f64");
+        assert_eq!(ConstantFloat::from(0.25f32).ty(), FloatTy::F32);
+        assert_eq!(ConstantFloat::from(0.25f32).f64_val(), 0.25);
+        assert_eq!(fl(ConstantFloat::from(1.0f64)).i64_cast(), None);
+
+        // Comparisons of values follow IEEE 754, but `==` compares bit patterns.
+        let zero = fl(ConstantFloat::from(0.0f64));
+        let neg_zero = fl(ConstantFloat::from(-0.0f32));
+        assert!(zero.value_eq(&neg_zero));
+        assert_ne!(zero, neg_zero);
+        let nan = fl(ConstantFloat::from(f64::NAN));
+        assert!(!nan.value_eq(&nan));
+        assert_eq!(nan, nan.clone());
+    }
+
+    #[test]
+    fn char_type() {
+        assert_eq!(Ty::Char.size(), 4);
+        assert_eq!(Ty::Char.align(), 4);
+        assert_eq!(Ty::Char.to_string(), "char");
+        assert_eq!(Ty::Char.abi_class(|_| None), AbiClass::Integer);
+
+        let c = Constant::Char('λ');
+        assert_eq!(c.to_string(), "'λ'");
+        assert_eq!(c.i64_cast(), Some(0x3bb));
+        assert!(c.value_eq(&Constant::Char('λ')));
+        assert!(!c.value_eq(&Constant::Int(ConstantInt::from(0x3bbu32))));
+    }
+
+    #[test]
+    fn fat_ref() {
+        // `&[u8]`.
+        let fr = Ty::FatRef((0, 1));
+        assert_eq!(
+            fr.size(),
+            2 * u64::try_from(mem::size_of::<usize>()).unwrap()
+        );
+        assert_eq!(fr.align(), Ty::Ref((0, 1)).align());
+        assert_eq!(fr.to_string(), "&[(0, 1)]");
+        // Fat references take two registers, which native calls can't use yet.
+        assert_eq!(fr.abi_class(|_| None), AbiClass::Memory);
+    }
+
+    #[test]
+    fn fn_ptr() {
+        assert_eq!(Ty::FnPtr.size(), Ty::Ref((0, 0)).size());
+        assert_eq!(Ty::FnPtr.to_string(), "fn ptr");
+
+        let mut call = Statement::Call(
+            CallOperand::FnPtr(Place::from(Local(3))),
+            vec![Operand::from(Local(1))],
+            Some(Place::from(Local(0))),
+            CallAbi {
+                args: vec![AbiClass::Integer],
+                ret: Some(AbiClass::Integer),
+            },
+        );
+        assert_eq!(call.to_string(), "$0 = call(fn_ptr($3), [$1])");
+        assert_eq!(call.used_locals(), vec![Local(3), Local(1)]);
+        call.map_locals(&mut |l| Local(l.0 + 10));
+        assert_eq!(call.to_string(), "$10 = call(fn_ptr($13), [$11])");
+    }
+
+    #[test]
+    fn native_align() {
+        assert_eq!(
+            Ty::UnsignedInt(UnsignedIntTy::Usize).align(),
+            u64::try_from(mem::align_of::<usize>()).unwrap()
+        );
+        assert_eq!(
+            Ty::SignedInt(SignedIntTy::I128).align(),
+            u64::try_from(mem::align_of::<i128>()).unwrap()
+        );
+        assert_eq!(
+            Ty::Ref((0, 0)).align(),
+            u64::try_from(mem::align_of::<&u8>()).unwrap()
+        );
+    }
+
+    #[test]
+    fn display_with() {
+        let types = Types {
+            crate_hash: 1234,
+            types: vec![
+                Ty::SignedInt(SignedIntTy::I32),
+                Ty::Ref((1234, 0)),
+                Ty::Ref((1234, 1)),
+                Ty::Ref((5678, 0)),
+                Ty::FatRef((1234, 0)),
+                Ty::Array(ArrayTy::new((1234, 1), &Ty::Ref((1234, 0)), 2)),
+                Ty::Ref((1234, 6)),
+                Ty::Ref((1234, 8)),
+                Ty::Ref((1234, 7)),
+            ],
+            thread_tracers: Vec::new(),
+        };
+        let show = |i: usize| {
+            let ty = &types.types[i];
+            (ty.to_string(), ty.display_with(&types).to_string())
+        };
+
+        assert_eq!(show(0), (String::from("i32"), String::from("i32")));
+        assert_eq!(show(1), (String::from("&(1234, 0)"), String::from("&i32")));
+        assert_eq!(show(2), (String::from("&(1234, 1)"), String::from("&&i32")));
+        // The referent is in another crate.
+        assert_eq!(
+            show(3),
+            (String::from("&(5678, 0)"), String::from("&(5678, 0)"))
+        );
+        assert_eq!(
+            show(4),
+            (String::from("&[(1234, 0)]"), String::from("&[i32]"))
+        );
+        assert_eq!(
+            show(5),
+            (
+                String::from("[(1234, 1); 2], align: 8, size: 16"),
+                String::from("[&i32; 2], align: 8, size: 16")
+            )
+        );
+        // Cyclic references stop at the first type which repeats.
+        assert_eq!(
+            show(6),
+            (String::from("&(1234, 6)"), String::from("&&(1234, 6)"))
+        );
+        assert_eq!(
+            show(7),
+            (String::from("&(1234, 8)"), String::from("&&&(1234, 8)"))
+        );
+    }
+
+    #[test]
+    fn index_projections() {
+        let idx = Place {
+            local: Local(1),
+            projection: vec![Projection::Index(Local(2))],
+        };
+        let const_idx = |from_end| Place {
+            local: Local(1),
+            projection: vec![Projection::ConstantIndex {
+                offset: 3,
+                min_length: 5,
+                from_end,
+            }],
+        };
+        assert_eq!(idx.to_string(), "($1)[$2]");
+        assert_eq!(const_idx(false).to_string(), "($1)[3 of 5]");
+        assert_eq!(const_idx(true).to_string(), "($1)[-3 of 5]");
+
+        // The index local is used, even when the indexed place is assigned to.
+        let stmt = Statement::Assign(idx.clone(), Rvalue::from(Local(3)));
+        assert_eq!(stmt.maybe_defined_locals(), vec![Local(1)]);
+        assert_eq!(stmt.used_locals(), vec![Local(3), Local(1), Local(2)]);
+        let stmt = Statement::Assign(
+            Place::from(Local(4)),
+            Rvalue::Use(Operand::Place(idx.clone())),
+        );
+        assert_eq!(stmt.used_locals(), vec![Local(1), Local(2), Local(4)]);
+
+        let mut renamed = idx;
+        renamed.map_locals(&mut |l| Local(l.0 * 10));
+        assert_eq!(renamed.to_string(), "($10)[$20]");
+
+        // `$1` is an array of `u8`, `$2` is a `u8`.
+        let tys = vec![
+            Ty::UnsignedInt(UnsignedIntTy::U8),
+            Ty::Array(ArrayTy::new((0, 0), &Ty::UnsignedInt(UnsignedIntTy::U8), 5)),
+        ];
+        let decls = vec![
+            LocalDecl { ty: (0, 0) },
+            LocalDecl { ty: (0, 1) },
+            LocalDecl { ty: (0, 0) },
+        ];
+        let resolve = |p: &Place| {
+            p.resolved_ty(
+                |l| decls.get(usize::try_from(l.0).unwrap()),
+                |t| tys.get(usize::try_from(t.1).unwrap()),
+            )
+        };
+        assert_eq!(resolve(&renamed), Err(PlaceError::NoLocalDecl(Local(10))));
+        assert_eq!(resolve(&const_idx(true)), Ok((0, 0)));
+        let bad = Place {
+            local: Local(2),
+            projection: vec![Projection::Index(Local(0))],
+        };
+        assert_eq!(resolve(&bad), Err(PlaceError::BadIndex((0, 0))));
+    }
+
+    #[test]
+    fn abi_class() {
+        let agg = |tys: Vec<TypeId>, size| {
+            Ty::Struct(StructTy {
+                fields: Fields {
+                    offsets: (0..tys.len())
+                        .map(|i| u64::try_from(i * 4).unwrap())
+                        .collect(),
+                    tys,
+                },
+                size_align: SizeAndAlign { size, align: 4 },
+            })
+        };
+        let tys = vec![
+            Ty::UnsignedInt(UnsignedIntTy::U64),
+            agg(vec![(0, 5), (0, 5)], 8),
+            agg(vec![(0, 5), (0, 5), (0, 5), (0, 5)], 16),
+            agg(vec![(0, 5), (0, 6)], 8),
+            agg(vec![(0, 1)], 8),
+            Ty::UnsignedInt(UnsignedIntTy::U32),
+            Ty::Float(FloatTy::F32),
+            agg(vec![(0, 99)], 8),
+        ];
+        let decls = (0..8).map(|i| LocalDecl { ty: (0, i) }).collect::<Vec<_>>();
+        let class = |op: Operand| {
+            op.abi_class(
+                |l| decls.get(usize::try_from(l.0).unwrap()),
+                |t| tys.get(usize::try_from(t.1).unwrap()),
+            )
+        };
+
+        assert_eq!(class(Operand::from(Local(0))), Ok(AbiClass::Integer));
+        assert_eq!(class(Operand::from(Local(1))), Ok(AbiClass::Integer));
+        // Aggregates larger than an eightbyte, or containing floats, are passed in memory.
+        assert_eq!(class(Operand::from(Local(2))), Ok(AbiClass::Memory));
+        assert_eq!(class(Operand::from(Local(3))), Ok(AbiClass::Memory));
+        // A nested aggregate.
+        assert_eq!(class(Operand::from(Local(4))), Ok(AbiClass::Integer));
+        // A field of a type we can't find.
+        assert_eq!(class(Operand::from(Local(7))), Ok(AbiClass::Memory));
+        assert_eq!(
+            class(Operand::Constant(Constant::Int(ConstantInt::from(1u128)))),
+            Ok(AbiClass::Integer)
+        );
+        assert_eq!(
+            class(Operand::from(Local(8))),
+            Err(PlaceError::NoLocalDecl(Local(8)))
+        );
+    }
 }
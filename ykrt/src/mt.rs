@@ -1,7 +1,8 @@
 #[cfg(test)]
 use std::time::Duration;
 use std::{
-    io,
+    convert::TryFrom,
+    io, mem,
     panic::{catch_unwind, resume_unwind, UnwindSafe},
     rc::Rc,
     sync::{
@@ -14,6 +15,10 @@ use std::{
 pub type HotThreshold = u32;
 const DEFAULT_HOT_THRESHOLD: HotThreshold = 50;
 
+// The number of buckets in the trace length histogram: one for empty traces, plus one for each
+// power of two a trace length can fall below.
+const TRACE_LENGTH_BUCKETS: usize = mem::size_of::<usize>() * 8 + 1;
+
 // The current meta-tracing phase of a given location in the end-user's code. Consists of a tag and
 // (optionally) a value. The tags are in the high order bits since we expect the most common tag is
 // PHASE_COMPILED which (one day) will have an index associated with it. By also making that tag
@@ -49,6 +54,7 @@ impl Location {
 /// Configure a meta-tracer. Note that a process can only have one meta-tracer active at one point.
 pub struct MTBuilder {
     hot_threshold: HotThreshold,
+    record_trace_lengths: bool,
 }
 
 impl MTBuilder {
@@ -56,13 +62,14 @@ impl MTBuilder {
     pub fn new() -> Self {
         Self {
             hot_threshold: DEFAULT_HOT_THRESHOLD,
+            record_trace_lengths: false,
         }
     }
 
     /// Consume the `MTBuilder` and create a meta-tracer, returning the
     /// [`MTThread`](struct.MTThread.html) representing the current thread.
     pub fn init(self) -> MTThread {
-        MTInner::init(self.hot_threshold)
+        MTInner::init(self.hot_threshold, self.record_trace_lengths)
     }
 
     /// Change this meta-tracer builder's `hot_threshold` value.
@@ -70,6 +77,14 @@ impl MTBuilder {
         self.hot_threshold = hot_threshold;
         self
     }
+
+    /// Change whether this meta-tracer records a histogram of trace lengths (see
+    /// [`MT::trace_length_histogram`](struct.MT.html#method.trace_length_histogram)). This is
+    /// off by default.
+    pub fn record_trace_lengths(mut self, record: bool) -> Self {
+        self.record_trace_lengths = record;
+        self
+    }
 }
 
 #[derive(Clone)]
@@ -85,6 +100,59 @@ impl MT {
         self.inner.hot_threshold.load(Ordering::Relaxed)
     }
 
+    /// Record that a trace of length `len` (as reported by `TirTrace::len()`) has been completed.
+    /// This does nothing unless recording of trace lengths has been enabled with
+    /// [`MTBuilder::record_trace_lengths`](struct.MTBuilder.html#method.record_trace_lengths).
+    pub fn record_trace_length(&self, len: usize) {
+        if let Some(hist) = &self.inner.trace_lengths {
+            // Empty traces go in bucket 0; otherwise the bucket is the number of bits needed to
+            // represent `len`, so bucket `i` holds lengths in `[2^(i - 1), 2^i)`.
+            let bucket = TRACE_LENGTH_BUCKETS - 1 - usize::try_from(len.leading_zeros()).unwrap();
+            hist[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Return a snapshot of the trace length histogram, or `None` if trace lengths aren't being
+    /// recorded. Element 0 counts empty traces, and element `i > 0` counts traces whose length is
+    /// in `[2^(i - 1), 2^i)`. Trailing empty buckets are omitted.
+    pub fn trace_length_histogram(&self) -> Option<Vec<usize>> {
+        self.inner.trace_lengths.as_ref().map(|hist| {
+            let mut counts = hist
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .collect::<Vec<_>>();
+            while counts.last() == Some(&0) {
+                counts.pop();
+            }
+            counts
+        })
+    }
+
+    /// Invalidate the compiled trace for `loc`, if there is one, so that `loc` starts counting
+    /// towards being traced again. Returns `true` if a compiled trace was invalidated, or `false`
+    /// if `loc` wasn't in the compiled phase (e.g. because another thread invalidated it first, or
+    /// because it is currently being traced, in which case it is left alone).
+    ///
+    /// Other threads may be executing the old trace while it is invalidated: they finish doing so
+    /// safely, and see the reset `Location` the next time they reach `loc`'s control point.
+    ///
+    /// FIXME: `PHASE_COMPILED` doesn't yet carry a compiled trace, so there is nothing to free.
+    /// Once it does, the trace can't be freed here, as other threads may still be running it.
+    /// Instead each thread will have to hold a reference count on the trace it's executing, with
+    /// the last thread to drop its reference freeing the trace.
+    pub fn invalidate(&self, loc: &Location) -> bool {
+        let pack = &loc.pack;
+        loop {
+            let lp = pack.load(Ordering::Acquire);
+            if lp & PHASE_TAG != PHASE_COMPILED {
+                return false;
+            }
+            if pack.compare_and_swap(lp, PHASE_COUNTING, Ordering::Release) == lp {
+                return true;
+            }
+        }
+    }
+
     /// Create a new thread that can be used in the meta-tracer: the new thread that is created is
     /// handed a [`MTThread`](struct.MTThread.html) from which the `MT` itself can be accessed.
     pub fn spawn<F, T>(&self, f: F) -> io::Result<JoinHandle<T>>
@@ -116,6 +184,8 @@ impl Drop for MT {
 struct MTInner {
     hot_threshold: AtomicU32,
     active_threads: AtomicUsize,
+    /// Counts of completed traces, bucketed by length. `None` if not recording.
+    trace_lengths: Option<Vec<AtomicUsize>>,
 }
 
 /// It's only safe to have one `MT` instance active at a time.
@@ -123,7 +193,7 @@ static MT_ACTIVE: AtomicBool = AtomicBool::new(false);
 
 impl MTInner {
     /// Create a new `MT`, wrapped immediately in an [`MTThread`](struct.MTThread.html).
-    fn init(hot_threshold: HotThreshold) -> MTThread {
+    fn init(hot_threshold: HotThreshold, record_trace_lengths: bool) -> MTThread {
         // A process can only have a single MT instance.
 
         // In non-testing, we panic if the user calls this method while an MT instance is active.
@@ -149,6 +219,15 @@ impl MTInner {
         let mtc = Self {
             hot_threshold: AtomicU32::new(hot_threshold),
             active_threads: AtomicUsize::new(1),
+            trace_lengths: if record_trace_lengths {
+                Some(
+                    (0..TRACE_LENGTH_BUCKETS)
+                        .map(|_| AtomicUsize::new(0))
+                        .collect(),
+                )
+            } else {
+                None
+            },
         };
         let mt = MT {
             inner: Arc::new(mtc),
@@ -288,6 +367,85 @@ mod tests {
         }
     }
 
+    #[test]
+    fn invalidate() {
+        let mtt = MTBuilder::new().hot_threshold(0).init();
+        let lp = Location::new();
+        assert!(!mtt.mt().invalidate(&lp));
+        mtt.control_point(&lp);
+        assert_eq!(lp.pack.load(Ordering::Relaxed), PHASE_TRACING);
+        assert!(!mtt.mt().invalidate(&lp));
+        mtt.control_point(&lp);
+        assert_eq!(lp.pack.load(Ordering::Relaxed), PHASE_COMPILED);
+        assert!(mtt.mt().invalidate(&lp));
+        assert_eq!(lp.pack.load(Ordering::Relaxed), PHASE_COUNTING);
+        assert!(!mtt.mt().invalidate(&lp));
+    }
+
+    #[test]
+    fn threaded_invalidate() {
+        let mtt = MTBuilder::new().hot_threshold(2).init();
+        let l_arc = Arc::new(Location::new());
+        let mut thrs = vec![];
+        for _ in 0..4 {
+            let l_arc_cl = Arc::clone(&l_arc);
+            let t = mtt
+                .mt()
+                .spawn(move |mtt| {
+                    for _ in 0..10000 {
+                        mtt.control_point(&*l_arc_cl);
+                        let tag = l_arc_cl.pack.load(Ordering::Relaxed) & PHASE_TAG;
+                        assert_ne!(tag, PHASE_TAG);
+                    }
+                })
+                .unwrap();
+            thrs.push(t);
+        }
+        let mut invalidated = 0;
+        while invalidated < 100 {
+            mtt.control_point(&l_arc);
+            if mtt.mt().invalidate(&l_arc) {
+                invalidated += 1;
+            }
+        }
+        for t in thrs {
+            t.join().unwrap();
+        }
+
+        // Once the readers are done, the location must still follow the usual phase changes.
+        mtt.mt().invalidate(&l_arc);
+        for _ in 0..3 {
+            mtt.control_point(&l_arc);
+        }
+        assert_eq!(l_arc.pack.load(Ordering::Relaxed), PHASE_TRACING);
+        mtt.control_point(&l_arc);
+        assert_eq!(l_arc.pack.load(Ordering::Relaxed), PHASE_COMPILED);
+    }
+
+    #[test]
+    fn trace_length_histogram() {
+        let mtt = MTBuilder::new().init();
+        mtt.mt().record_trace_length(10);
+        assert_eq!(mtt.mt().trace_length_histogram(), None);
+        drop(mtt);
+
+        let mtt = MTBuilder::new().record_trace_lengths(true).init();
+        let mt = mtt.mt();
+        assert_eq!(mt.trace_length_histogram(), Some(vec![]));
+        for len in &[0, 1, 2, 3, 4, 7, 8, 100] {
+            mt.record_trace_length(*len);
+        }
+        assert_eq!(
+            mt.trace_length_histogram(),
+            Some(vec![1, 1, 2, 2, 1, 0, 0, 1])
+        );
+        mt.record_trace_length(usize::MAX);
+        assert_eq!(
+            mt.trace_length_histogram().unwrap().len(),
+            TRACE_LENGTH_BUCKETS
+        );
+    }
+
     #[bench]
     fn bench_single_threaded_control_point(b: &mut Bencher) {
         let mtt = MTBuilder::new().init();
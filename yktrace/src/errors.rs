@@ -1,4 +1,7 @@
-use std::fmt::{self, Display, Formatter};
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter}
+};
 
 #[derive(Debug)]
 /// Reasons that a trace can be invalidated.
@@ -7,7 +10,15 @@ pub enum InvalidTraceError {
     /// The string inside is the binary symbol name in which the location appears.
     NoSir(String),
     /// Something went wrong in the compiler's tracing code
-    InternalError
+    InternalError,
+    /// The trace contains no SIR locations.
+    EmptyTrace,
+    /// The arguments of a call don't match the parameters of the (inlined) callee.
+    /// The string inside is the binary symbol name of the callee.
+    CallSignatureMismatch(String),
+    /// A location in the trace refers to a block which doesn't exist.
+    /// The string inside is the binary symbol name in which the location appears.
+    BadBlockIndex(String, u32)
 }
 
 impl InvalidTraceError {
@@ -23,7 +34,50 @@ impl Display for InvalidTraceError {
             InvalidTraceError::NoSir(symbol_name) => {
                 write!(f, "No SIR for location in symbol: {}", symbol_name)
             }
-            InvalidTraceError::InternalError => write!(f, "Internal tracing error")
+            InvalidTraceError::InternalError => write!(f, "Internal tracing error"),
+            InvalidTraceError::EmptyTrace => write!(f, "Empty trace"),
+            InvalidTraceError::CallSignatureMismatch(symbol_name) => {
+                write!(
+                    f,
+                    "Call arguments don't match parameters of: {}",
+                    symbol_name
+                )
+            }
+            InvalidTraceError::BadBlockIndex(symbol_name, bb_idx) => {
+                write!(f, "No block {} in symbol: {}", bb_idx, symbol_name)
+            }
         }
     }
 }
+
+impl Error for InvalidTraceError {}
+
+#[cfg(test)]
+mod tests {
+    use super::InvalidTraceError;
+    use std::error::Error;
+
+    #[test]
+    fn display() {
+        let errs: Vec<Box<dyn Error>> = vec![
+            Box::new(InvalidTraceError::no_sir("foo")),
+            Box::new(InvalidTraceError::InternalError),
+            Box::new(InvalidTraceError::EmptyTrace),
+            Box::new(InvalidTraceError::CallSignatureMismatch(String::from(
+                "bar"
+            ))),
+            Box::new(InvalidTraceError::BadBlockIndex(String::from("baz"), 3)),
+        ];
+        let msgs = errs.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            msgs,
+            vec![
+                "No SIR for location in symbol: foo",
+                "Internal tracing error",
+                "Empty trace",
+                "Call arguments don't match parameters of: bar",
+                "No block 3 in symbol: baz"
+            ]
+        );
+    }
+}
@@ -24,26 +24,37 @@ impl SirTrace for HWTSirTrace {
     }
 
     fn input(&self) -> Local {
-        let blk = (self as &dyn SirTrace).into_iter().next().unwrap();
-        let body = &SIR.bodies[&blk.symbol_name];
+        let blk = (self as &dyn SirTrace).iter().next().unwrap();
+        let body = SIR.bodies.for_loc(blk).unwrap();
         body.trace_inputs_local.unwrap()
     }
 }
 
 /// Hardware thread tracer.
 struct HWTThreadTracer {
-    ttracer: Box<dyn hwtracer::ThreadTracer>
+    ttracer: Box<dyn hwtracer::ThreadTracer>,
+    /// Whether the backend is still tracing.
+    active: bool
 }
 
 impl ThreadTracerImpl for HWTThreadTracer {
     #[trace_tail]
     fn stop_tracing(&mut self) -> Result<Box<dyn SirTrace>, InvalidTraceError> {
         let hwtrace = self.ttracer.stop_tracing().unwrap();
+        self.active = false;
         let mt = HWTMapper::new();
         mt.map(hwtrace)
             .map_err(|_| InvalidTraceError::InternalError)
             .and_then(|sirtrace| Ok(Box::new(HWTSirTrace { sirtrace }) as Box<dyn SirTrace>))
     }
+
+    fn abandon(&mut self) {
+        if self.active {
+            self.active = false;
+            // Dropping the raw trace frees its buffers without mapping it.
+            let _ = self.ttracer.stop_tracing();
+        }
+    }
 }
 
 #[trace_head]
@@ -52,7 +63,10 @@ pub fn start_tracing() -> ThreadTracer {
     let mut ttracer = (*tracer).thread_tracer();
     ttracer.start_tracing().expect("Failed to start tracer.");
     ThreadTracer {
-        t_impl: Box::new(HWTThreadTracer { ttracer })
+        t_impl: Box::new(HWTThreadTracer {
+            ttracer,
+            active: true
+        })
     }
 }
 
@@ -89,6 +103,16 @@ mod tests {
         test_helpers::test_in_bounds_trace_indices(TRACING_KIND);
     }
 
+    #[test]
+    fn test_oob_trace_get() {
+        test_helpers::test_oob_trace_get(TRACING_KIND);
+    }
+
+    #[test]
+    fn test_drop_without_stop() {
+        test_helpers::test_drop_without_stop(TRACING_KIND);
+    }
+
     #[test]
     fn test_trace_iterator() {
         test_helpers::test_trace_iterator(TRACING_KIND);
@@ -13,8 +13,9 @@ mod hwt;
 pub mod sir;
 pub mod tir;
 
-use errors::InvalidTraceError;
+pub use errors::InvalidTraceError;
 use sir::{SirLoc, SirTrace};
+use tir::TirTrace;
 
 /// The different ways by which we can collect a trace.
 #[derive(Clone, Copy)]
@@ -26,6 +27,9 @@ pub enum TracingKind {
 }
 
 /// Represents a thread which is currently tracing.
+///
+/// Dropping a `ThreadTracer` without calling `stop_tracing()` abandons the trace: tracing is
+/// stopped and anything recorded so far is discarded.
 #[thread_tracer]
 pub struct ThreadTracer {
     /// The tracing implementation.
@@ -38,6 +42,21 @@ impl ThreadTracer {
     pub fn stop_tracing(mut self) -> Result<Box<dyn SirTrace>, InvalidTraceError> {
         self.t_impl.stop_tracing()
     }
+
+    /// Stops tracing on the current thread and lowers the SIR trace to a TIR trace. The SIR trace
+    /// is freed as soon as the TIR trace has been built. Use `stop_tracing()` to get at the SIR
+    /// trace itself.
+    #[trace_tail]
+    pub fn stop_and_lower(self) -> Result<TirTrace, InvalidTraceError> {
+        let sir_trace = self.stop_tracing()?;
+        TirTrace::new(&*sir_trace)
+    }
+}
+
+impl Drop for ThreadTracer {
+    fn drop(&mut self) {
+        self.t_impl.abandon();
+    }
 }
 
 // An generic interface which tracing backends must fulfill.
@@ -45,21 +64,53 @@ trait ThreadTracerImpl {
     /// Stops tracing on the current thread, returning the SIR trace on success.
     #[trace_tail]
     fn stop_tracing(&mut self) -> Result<Box<dyn SirTrace>, InvalidTraceError>;
+
+    /// Stops tracing on the current thread if it hasn't been stopped already, discarding the
+    /// trace and freeing any resources held by the backend.
+    fn abandon(&mut self);
 }
 
 /// Start tracing on the current thread using the specified tracing kind.
-/// If `None` is passed, then an appropriate tracing kind will be selected; by passing `Some(...)`,
-/// a specific kind can be chosen. Any given thread can at most one active tracer; calling
-/// `start_tracing()` on a thread where there is already an active tracer leads to undefined
-/// behaviour.
+/// If `None` is passed, then the tracing kind that the compiler was configured for will be
+/// selected; by passing `Some(...)`, a specific kind can be chosen. Any given thread can at most
+/// one active tracer; calling `start_tracing()` on a thread where there is already an active
+/// tracer leads to undefined behaviour.
 #[trace_head]
 pub fn start_tracing(kind: Option<TracingKind>) -> ThreadTracer {
+    #[cfg(tracermode = "sw")]
+    let kind = kind.unwrap_or(TracingKind::SoftwareTracing);
+    #[cfg(not(tracermode = "sw"))]
+    let kind = kind.unwrap_or(TracingKind::HardwareTracing);
     match kind {
-        Some(TracingKind::SoftwareTracing) => unimplemented!("software tracing is broken"),
-        None | Some(TracingKind::HardwareTracing) => hwt::start_tracing()
+        TracingKind::SoftwareTracing => unimplemented!("software tracing is broken"),
+        TracingKind::HardwareTracing => hwt::start_tracing()
     }
 }
 
+/// Traces the evaluation of the expression `$e` on the current thread, using the tracing kind that
+/// the compiler was configured for, and lowers the result to a TIR trace. Evaluates to a
+/// `Result<TirTrace, InvalidTraceError>`.
+///
+/// The value of `$e` is passed through `black_box()` so that the code being traced isn't
+/// optimised away. Inputs which shouldn't be constant folded into the trace must be wrapped in
+/// `black_box()` by the caller, e.g. `trace!(work(black_box(3), black_box(13)))`.
+#[macro_export]
+macro_rules! trace {
+    ($e: expr) => {{
+        let tracer = $crate::start_tracing(None);
+        $crate::__black_box($e);
+        tracer.stop_and_lower()
+    }};
+}
+
+/// Used by `trace!()`, as `test::black_box()` can't be named from crates which don't enable the
+/// `test` feature.
+#[doc(hidden)]
+#[inline(always)]
+pub fn __black_box<T>(x: T) -> T {
+    test::black_box(x)
+}
+
 /// The bodies of tests that we want to run on all tracing kinds live in here.
 #[cfg(test)]
 mod test_helpers {
@@ -138,16 +189,42 @@ mod test_helpers {
 
         for i in 0..trace.raw_len() {
             trace.raw_loc(i);
+            assert!(trace.get(i).is_some());
         }
     }
 
+    /// Test that non-panicking accesses past the end of a trace return `None`.
+    pub(crate) fn test_oob_trace_get(kind: TracingKind) {
+        let mut th = start_tracing(Some(kind));
+        black_box(work(10));
+        let trace = th.t_impl.stop_tracing().unwrap();
+        let len = trace.raw_len();
+        assert!(trace.get(len).is_none());
+        assert!(trace.get(100000).is_none());
+    }
+
+    /// Test that dropping a tracer without stopping it stops the backend, so that tracing can be
+    /// started again on the same thread.
+    pub(crate) fn test_drop_without_stop(kind: TracingKind) {
+        let th = start_tracing(Some(kind));
+        black_box(work(10));
+        drop(th);
+
+        let mut th = start_tracing(Some(kind));
+        black_box(work(10));
+        let trace = th.t_impl.stop_tracing().unwrap();
+        assert!(trace.raw_len() > 0);
+    }
+
     /// Test iteration over a trace.
     pub(crate) fn test_trace_iterator(kind: TracingKind) {
         let mut th = start_tracing(Some(kind));
         black_box(work(10));
         let trace = th.t_impl.stop_tracing().unwrap();
         // The length of the iterator will be shorter due to trimming.
-        assert!(trace.into_iter().count() < trace.raw_len());
+        let locs = trace.iter().collect::<Vec<_>>();
+        assert!(locs.len() < trace.raw_len());
+        assert_eq!(locs.len(), trace.into_iter().count());
     }
 
     #[test]
@@ -163,10 +240,7 @@ mod test_helpers {
             let mut found_start_code = false;
             let mut found_stop_code = false;
             for loc in locs {
-                let body = SIR
-                    .bodies
-                    .get(&loc.symbol_name)
-                    .expect("No SIR for the location");
+                let body = SIR.bodies.for_loc(loc).expect("No SIR for the location");
 
                 if body.flags & bodyflags::TRACE_HEAD != 0 {
                     found_start_code = true;
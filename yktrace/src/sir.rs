@@ -4,20 +4,21 @@ use core::yk::SirLoc as CoreSirLoc;
 use elf;
 use fallible_iterator::FallibleIterator;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     convert::TryFrom,
     env,
     fmt::{self, Debug, Display, Write},
+    hash::{Hash, Hasher},
     io::Cursor,
     iter::Iterator
 };
-use ykpack::{bodyflags, Body, Decoder, Local, Pack, Ty}; // FIXME kill.
+use ykpack::{bodyflags, Body, Decoder, Fields, Local, Pack, Ty}; // FIXME kill.
 
 /// The serialised IR loaded in from disk. One of these structures is generated in the above
 /// `lazy_static` and is shared immutably for all threads.
 pub struct Sir {
     /// Lets us map a symbol name to a SIR body.
-    pub bodies: HashMap<String, Body>,
+    pub bodies: Bodies,
     // Interesting locations that we need quick access to.
     pub markers: SirMarkers,
     /// SIR Local variable types, keyed by crate hash.
@@ -27,13 +28,182 @@ pub struct Sir {
 }
 
 impl Sir {
+    /// Builds a `Sir` from decoded packs, indexing the bodies by symbol name and the types by
+    /// crate hash. This is how `SIR` is loaded from the current executable, but it may also be
+    /// used to build a `Sir` from fixtures.
+    ///
+    /// Panics if a body fails validation.
+    pub fn from_packs<I: IntoIterator<Item = Pack>>(packs: I) -> Self {
+        let mut bodies = Bodies::default();
+        let mut types = HashMap::new();
+        let mut trace_heads = Vec::new();
+        let mut trace_tails = Vec::new();
+        let mut thread_tracers = HashSet::new();
+
+        for pack in packs {
+            match pack {
+                Pack::Body(body) => {
+                    if let Err(e) = body.validate() {
+                        panic!("Invalid SIR for {}: {}", body.symbol_name, e);
+                    }
+
+                    // Cache some locations that we need quick access to.
+                    if body.flags & bodyflags::TRACE_HEAD != 0 {
+                        trace_heads.push(body.symbol_name.clone());
+                    }
+
+                    if body.flags & bodyflags::TRACE_TAIL != 0 {
+                        trace_tails.push(body.symbol_name.clone());
+                    }
+
+                    // Due to the way Rust compiles stuff, duplicates may exist. Where duplicates
+                    // exist, the functions will be identical, but may have different (but
+                    // equivalent) types. This is because types too may be duplicated using a
+                    // different crate hash.
+                    bodies.insert_if_absent(body);
+                }
+                Pack::Types(ts) => {
+                    let old = types.insert(ts.crate_hash, ts.types);
+                    debug_assert!(old.is_none()); // There's one `Types` pack per crate.
+                    for idx in ts.thread_tracers {
+                        thread_tracers.insert((ts.crate_hash, idx));
+                    }
+                }
+            }
+        }
+
+        let markers = SirMarkers {
+            trace_heads,
+            trace_tails
+        };
+        Sir {
+            bodies,
+            markers,
+            types,
+            thread_tracers
+        }
+    }
+
     pub fn ty(&self, id: &ykpack::TypeId) -> &ykpack::Ty {
         &self.types[&id.0][usize::try_from(id.1).unwrap()]
     }
 
+    /// Like `ty()`, but returns `None` if there's no type with the specified ID.
+    pub fn get_ty(&self, id: &ykpack::TypeId) -> Option<&ykpack::Ty> {
+        self.types.get(&id.0)?.get(usize::try_from(id.1).unwrap())
+    }
+
     pub fn is_thread_tracer_ty(&self, id: &ykpack::TypeId) -> bool {
         self.thread_tracers.contains(id)
     }
+
+    /// Returns true if the types `a` and `b` are structurally the same. Since types may be
+    /// duplicated across crates (with different crate hashes), comparing type IDs isn't enough.
+    pub fn tys_equivalent(&self, a: &ykpack::TypeId, b: &ykpack::TypeId) -> bool {
+        self.tys_equivalent_inner(a, b, &mut Vec::new())
+    }
+
+    /// Does the work for `tys_equivalent()`. `assumed` holds the pairs of types currently being
+    /// compared further up the stack, which are assumed to be equivalent so that comparing
+    /// recursive types terminates.
+    fn tys_equivalent_inner(
+        &self,
+        a: &ykpack::TypeId,
+        b: &ykpack::TypeId,
+        assumed: &mut Vec<(ykpack::TypeId, ykpack::TypeId)>
+    ) -> bool {
+        if a == b || assumed.contains(&(*a, *b)) {
+            return true;
+        }
+        let (ty_a, ty_b) = match (self.get_ty(a), self.get_ty(b)) {
+            (Some(ty_a), Some(ty_b)) => (ty_a, ty_b),
+            _ => return false
+        };
+
+        assumed.push((*a, *b));
+        let mut fields_equivalent = |fa: &Fields, fb: &Fields| {
+            fa.offsets == fb.offsets
+                && fa.tys.len() == fb.tys.len()
+                && fa
+                    .tys
+                    .iter()
+                    .zip(fb.tys.iter())
+                    .all(|(ta, tb)| self.tys_equivalent_inner(ta, tb, assumed))
+        };
+        let res = match (ty_a, ty_b) {
+            (Ty::Struct(sa), Ty::Struct(sb)) => {
+                sa.size_align == sb.size_align && fields_equivalent(&sa.fields, &sb.fields)
+            }
+            (Ty::Tuple(ta), Ty::Tuple(tb)) => {
+                ta.size_align == tb.size_align && fields_equivalent(&ta.fields, &tb.fields)
+            }
+            (Ty::Array(aa), Ty::Array(ab)) => {
+                aa.len == ab.len
+                    && aa.size_align == ab.size_align
+                    && self.tys_equivalent_inner(&aa.elem, &ab.elem, assumed)
+            }
+            (Ty::Enum(ea), Ty::Enum(eb)) => {
+                ea.discr_offset == eb.discr_offset
+                    && ea.size_align == eb.size_align
+                    && ea.variants.len() == eb.variants.len()
+                    // Discriminants are integers, so there's nothing to recurse into.
+                    && self.get_ty(&ea.discr_ty) == self.get_ty(&eb.discr_ty)
+                    && ea
+                        .variants
+                        .iter()
+                        .zip(eb.variants.iter())
+                        .all(|(va, vb)| fields_equivalent(va, vb))
+            }
+            (Ty::Ref(ra), Ty::Ref(rb)) | (Ty::FatRef(ra), Ty::FatRef(rb)) => {
+                self.tys_equivalent_inner(ra, rb, assumed)
+            }
+            _ => ty_a == ty_b
+        };
+        assumed.pop();
+        res
+    }
+}
+
+/// Hashes a symbol name. `SirLoc`s carry the hash of their symbol name so that looking up their
+/// SIR body doesn't require hashing a string each time.
+pub fn symbol_hash(sym: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    sym.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// SIR bodies, keyed by the hash of their symbol name (see `symbol_hash()`). Looking up the body
+/// of a `SirLoc` uses the loc's precomputed hash, so no string hashing is required.
+#[derive(Default)]
+pub struct Bodies {
+    map: HashMap<u64, Vec<Body>>
+}
+
+impl Bodies {
+    /// Inserts a body, unless a body with the same symbol name is already present.
+    fn insert_if_absent(&mut self, body: Body) {
+        let bucket = self.map.entry(symbol_hash(&body.symbol_name)).or_default();
+        if !bucket.iter().any(|b| b.symbol_name == body.symbol_name) {
+            bucket.push(body);
+        }
+    }
+
+    /// Looks up the body for the symbol `sym`.
+    pub fn get(&self, sym: &str) -> Option<&Body> {
+        self.get_hashed(symbol_hash(sym), sym)
+    }
+
+    /// Looks up the body for the symbol `sym`, whose hash (as computed by `symbol_hash()`) is
+    /// `hash`.
+    pub fn get_hashed(&self, hash: u64, sym: &str) -> Option<&Body> {
+        // The bucket almost always has a single entry. Comparing the name rules out collisions.
+        self.map.get(&hash)?.iter().find(|b| b.symbol_name == sym)
+    }
+
+    /// Looks up the body containing the location `loc`.
+    pub fn for_loc(&self, loc: &SirLoc) -> Option<&Body> {
+        self.get_hashed(loc.symbol_hash, &loc.symbol_name)
+    }
 }
 
 /// Records interesting locations required for trace manipulation.
@@ -60,61 +230,36 @@ lazy_static! {
     pub static ref SIR: Sir = {
         let ef = elf::File::open_path(env::current_exe().unwrap()).unwrap();
 
-        // We iterate over ELF sections, looking for ones which contain SIR and loading it into
+        // We iterate over ELF sections, looking for ones which contain SIR and decoding it into
         // memory.
-        let mut bodies = HashMap::new();
-        let mut types = HashMap::new();
-        let mut trace_heads = Vec::new();
-        let mut trace_tails = Vec::new();
-        let mut thread_tracers = HashSet::new();
+        let mut packs = Vec::new();
         for sec in &ef.sections {
             if sec.shdr.name.starts_with(".yksir_") {
                 let mut curs = Cursor::new(&sec.data);
                 let mut dec = Decoder::from(&mut curs);
 
                 while let Some(pack) = dec.next().unwrap() {
-                    match pack {
-                        Pack::Body(body) => {
-                            // Cache some locations that we need quick access to.
-                            if body.flags & bodyflags::TRACE_HEAD != 0 {
-                                trace_heads.push(body.symbol_name.clone());
-                            }
-
-                            if body.flags & bodyflags::TRACE_TAIL != 0 {
-                                trace_tails.push(body.symbol_name.clone());
-                            }
-
-                            // Due to the way Rust compiles stuff, duplicates may exist. Where
-                            // duplicates exist, the functions will be identical, but may have
-                            // different (but equivalent) types. This is because types too may be
-                            // duplicated using a different crate hash.
-                            bodies.entry(body.symbol_name.clone()).or_insert_with(|| body);
-                        },
-                        Pack::Types(ts) => {
-                            let old = types.insert(ts.crate_hash, ts.types);
-                            debug_assert!(old.is_none()); // There's one `Types` pack per crate.
-                            for idx in ts.thread_tracers {
-                                thread_tracers.insert((ts.crate_hash, idx));
-                            }
-                        },
-                    }
+                    packs.push(pack);
                 }
             }
         }
 
-        assert!(!trace_heads.is_empty(), "no trace heads found!");
-        assert!(!trace_tails.is_empty(), "no trace tails found!");
-        let markers = SirMarkers { trace_heads, trace_tails };
-
-        Sir {bodies, markers, types, thread_tracers}
+        let sir = Sir::from_packs(packs);
+        assert!(!sir.markers.trace_heads.is_empty(), "no trace heads found!");
+        assert!(!sir.markers.trace_tails.is_empty(), "no trace tails found!");
+        sir
     };
 }
 
 /// The same as core::SirLoc, just with a String representation of the symbol name and with the
 /// traits we were disallowed from using in libcore.
-#[derive(Debug, Hash, Eq, PartialEq)]
+#[derive(Debug, Eq)]
 pub struct SirLoc {
-    pub symbol_name: String,
+    // The symbol name and its hash are private, so that they can't get out of sync. Equality,
+    // hashing and body lookups trust the hash.
+    symbol_name: String,
+    /// The hash of `symbol_name`, as computed by `symbol_hash()`.
+    symbol_hash: u64,
     pub bb_idx: u32,
     // Virtual address of this location.
     pub addr: Option<u64>
@@ -122,22 +267,49 @@ pub struct SirLoc {
 
 impl From<&CoreSirLoc> for SirLoc {
     fn from(core_loc: &CoreSirLoc) -> SirLoc {
-        SirLoc {
-            symbol_name: String::from_utf8(core_loc.symbol_name().to_vec()).unwrap(),
-            bb_idx: core_loc.bb_idx(),
-            addr: None
-        }
+        SirLoc::new(
+            String::from_utf8(core_loc.symbol_name().to_vec()).unwrap(),
+            core_loc.bb_idx(),
+            None
+        )
     }
 }
 
 impl SirLoc {
     pub fn new(symbol_name: String, bb_idx: u32, addr: Option<u64>) -> Self {
         Self {
+            symbol_hash: symbol_hash(&symbol_name),
             symbol_name,
             bb_idx,
             addr
         }
     }
+
+    /// Returns the name of the symbol this location is in.
+    pub fn symbol_name(&self) -> &str {
+        &self.symbol_name
+    }
+
+    /// Returns true if `self` and `other` are locations in the same symbol.
+    pub fn same_symbol(&self, other: &SirLoc) -> bool {
+        // Only compare the names if the hashes match, to rule out a collision.
+        self.symbol_hash == other.symbol_hash && self.symbol_name == other.symbol_name
+    }
+}
+
+impl PartialEq for SirLoc {
+    fn eq(&self, other: &Self) -> bool {
+        self.bb_idx == other.bb_idx && self.addr == other.addr && self.same_symbol(other)
+    }
+}
+
+impl Hash for SirLoc {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Equal locations have equal symbol hashes, so there's no need to hash the name again.
+        self.symbol_hash.hash(state);
+        self.bb_idx.hash(state);
+        self.addr.hash(state);
+    }
 }
 
 /// Generic representation of a trace of SIR block locations.
@@ -145,19 +317,41 @@ pub trait SirTrace: Debug {
     /// Returns the length of the *raw* (untrimmed) trace, measured in SIR locations.
     fn raw_len(&self) -> usize;
 
+    /// Returns true if the *raw* (untrimmed) trace contains no SIR locations.
+    fn is_empty(&self) -> bool {
+        self.raw_len() == 0
+    }
+
     /// Returns the SIR location at index `idx` in the *raw* (untrimmed) trace.
     fn raw_loc(&self, idx: usize) -> &SirLoc;
 
+    /// Returns the SIR location at index `idx` in the *raw* (untrimmed) trace, or `None` if `idx`
+    /// is out of bounds. Unlike `raw_loc()`, this never panics.
+    fn get(&self, idx: usize) -> Option<&SirLoc> {
+        if idx < self.raw_len() {
+            Some(self.raw_loc(idx))
+        } else {
+            None
+        }
+    }
+
     /// Returns the local variable containing the trace inputs tuple.
     fn input(&self) -> Local;
 }
 
+impl<'t> dyn SirTrace + 't {
+    /// Returns an iterator over the *trimmed* trace.
+    pub fn iter(&self) -> SirTraceIterator<'_> {
+        SirTraceIterator::new(self)
+    }
+}
+
 impl<'a> IntoIterator for &'a dyn SirTrace {
     type Item = &'a SirLoc;
     type IntoIter = SirTraceIterator<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        SirTraceIterator::new(self)
+        self.iter()
     }
 }
 
@@ -165,7 +359,7 @@ impl<'a> IntoIterator for &'a dyn SirTrace {
 pub fn sir_trace_str<'a>(trace: &'a dyn SirTrace, trimmed: bool, show_blocks: bool) -> String {
     let locs: Vec<&SirLoc> = match trimmed {
         false => (0..(trace.raw_len())).map(|i| trace.raw_loc(i)).collect(),
-        true => trace.into_iter().collect()
+        true => trace.iter().collect()
     };
 
     let mut res = String::new();
@@ -175,7 +369,7 @@ pub fn sir_trace_str<'a>(trace: &'a dyn SirTrace, trimmed: bool, show_blocks: bo
     for loc in locs {
         write!(res_r, "[{}] bb={}, flags=[", loc.symbol_name, loc.bb_idx).unwrap();
 
-        let body = SIR.bodies.get(&loc.symbol_name);
+        let body = SIR.bodies.for_loc(loc);
         if let Some(body) = body {
             if body.flags & bodyflags::TRACE_HEAD != 0 {
                 write!(res_r, "HEAD ").unwrap();
@@ -187,15 +381,10 @@ pub fn sir_trace_str<'a>(trace: &'a dyn SirTrace, trimmed: bool, show_blocks: bo
         writeln!(res_r, "]").unwrap();
 
         if show_blocks {
-            if let Some(body) = body {
-                writeln!(
-                    res_r,
-                    "{}:",
-                    body.blocks[usize::try_from(loc.bb_idx).unwrap()]
-                )
-                .unwrap();
-            } else {
-                writeln!(res_r, "    <no sir>").unwrap();
+            match body.map(|b| b.block(loc.bb_idx)) {
+                Some(Some(blk)) => writeln!(res_r, "{}:", blk).unwrap(),
+                Some(None) => writeln!(res_r, "    <bad block index>").unwrap(),
+                None => writeln!(res_r, "    <no sir>").unwrap()
             }
         }
     }
@@ -256,3 +445,120 @@ impl<'a> Iterator for SirTraceIterator<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Bodies, Sir, SirLoc};
+    use std::collections::HashMap;
+    use test::{black_box, Bencher};
+    use ykpack::{bodyflags, test_utils::TypesBuilder, Body, Pack, Ty, UnsignedIntTy};
+
+    fn body(sym: String) -> Body {
+        Body {
+            symbol_name: sym,
+            blocks: Vec::new(),
+            flags: 0,
+            trace_inputs_local: None,
+            local_decls: Vec::new()
+        }
+    }
+
+    // Mangled symbol names are long and share long prefixes.
+    fn sym(i: usize) -> String {
+        format!(
+            "_ZN7yktrace3tir5tests18some_function_name{}17h0123456789abcdefE",
+            i
+        )
+    }
+
+    const NUM_SYMS: usize = 1000;
+
+    #[test]
+    fn from_packs() {
+        let mut tys = TypesBuilder::new(7);
+        let u8_ty = tys.add("u8", Ty::UnsignedInt(UnsignedIntTy::U8));
+        tys.add("tracer", Ty::Unimplemented(String::from("tracer")));
+        tys.thread_tracer("tracer");
+
+        let mut head = body(String::from("head"));
+        head.flags = bodyflags::TRACE_HEAD;
+        let packs = vec![
+            Pack::Body(head),
+            Pack::Types(tys.build()),
+            Pack::Body(body(sym(0))),
+            // A duplicate body is ignored.
+            Pack::Body(body(sym(0))),
+        ];
+        let sir = Sir::from_packs(packs);
+
+        assert!(sir.bodies.get("head").is_some());
+        assert!(sir.bodies.get(&sym(0)).is_some());
+        assert!(sir.bodies.get(&sym(1)).is_none());
+        assert_eq!(sir.markers.trace_heads, vec![String::from("head")]);
+        assert!(sir.markers.trace_tails.is_empty());
+        assert_eq!(
+            sir.get_ty(&u8_ty),
+            Some(&Ty::UnsignedInt(UnsignedIntTy::U8))
+        );
+        assert_eq!(sir.get_ty(&(7, 2)), None);
+        assert!(sir.is_thread_tracer_ty(&(7, 1)));
+        assert!(!sir.is_thread_tracer_ty(&u8_ty));
+    }
+
+    #[test]
+    fn bodies_lookup() {
+        let mut bodies = Bodies::default();
+        for i in 0..NUM_SYMS {
+            bodies.insert_if_absent(body(sym(i)));
+        }
+        for i in 0..NUM_SYMS {
+            let loc = SirLoc::new(sym(i), 0, None);
+            assert_eq!(bodies.for_loc(&loc).unwrap().symbol_name, sym(i));
+            assert_eq!(bodies.get(&sym(i)).unwrap().symbol_name, sym(i));
+        }
+        assert!(bodies.get("nonexistent").is_none());
+    }
+
+    #[test]
+    fn sir_loc_eq() {
+        let a = SirLoc::new(sym(1), 2, None);
+        assert_eq!(a.symbol_name(), sym(1));
+        assert_eq!(a, SirLoc::new(sym(1), 2, None));
+        assert!(a.same_symbol(&SirLoc::new(sym(1), 3, None)));
+        assert_ne!(a, SirLoc::new(sym(1), 3, None));
+        assert_ne!(a, SirLoc::new(sym(2), 2, None));
+    }
+
+    fn bench_locs() -> Vec<SirLoc> {
+        (0..NUM_SYMS)
+            .map(|i| SirLoc::new(sym(i), 0, None))
+            .collect()
+    }
+
+    #[bench]
+    fn bench_lookup_string_keyed(b: &mut Bencher) {
+        let bodies = (0..NUM_SYMS)
+            .map(|i| (sym(i), body(sym(i))))
+            .collect::<HashMap<String, Body>>();
+        let locs = bench_locs();
+        b.iter(|| {
+            for loc in &locs {
+                black_box(bodies.get(&loc.symbol_name));
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_lookup_hash_keyed(b: &mut Bencher) {
+        let mut bodies = Bodies::default();
+        for i in 0..NUM_SYMS {
+            bodies.insert_if_absent(body(sym(i)));
+        }
+        let locs = bench_locs();
+        b.iter(|| {
+            for loc in &locs {
+                black_box(bodies.for_loc(loc));
+            }
+        });
+    }
+}
@@ -43,23 +43,36 @@ impl SirTrace for SWTSirTrace {
 }
 
 /// Softare thread tracer.
-struct SWTThreadTracer;
+struct SWTThreadTracer {
+    /// Whether the backend is still tracing.
+    active: bool
+}
 
 impl ThreadTracerImpl for SWTThreadTracer {
     #[trace_tail]
     fn stop_tracing(&mut self) -> Result<Box<dyn SirTrace>, InvalidTraceError> {
+        self.active = false;
         match swt::stop_tracing() {
             None => Err(InvalidTraceError::InternalError),
             Some((buf, len)) => Ok(Box::new(SWTSirTrace::from_buf(buf, len)) as Box<dyn SirTrace>)
         }
     }
+
+    fn abandon(&mut self) {
+        if self.active {
+            self.active = false;
+            if let Some((buf, _)) = swt::stop_tracing() {
+                unsafe { libc::free(buf as *mut libc::c_void) };
+            }
+        }
+    }
 }
 
 #[trace_head]
 pub fn start_tracing() -> ThreadTracer {
     swt::start_tracing();
     ThreadTracer {
-        t_impl: Box::new(SWTThreadTracer {})
+        t_impl: Box::new(SWTThreadTracer { active: true })
     }
 }
 
@@ -96,6 +109,16 @@ mod tests {
         test_helpers::test_in_bounds_trace_indices(TRACING_KIND);
     }
 
+    #[test]
+    fn test_oob_trace_get() {
+        test_helpers::test_oob_trace_get(TRACING_KIND);
+    }
+
+    #[test]
+    fn test_drop_without_stop() {
+        test_helpers::test_drop_without_stop(TRACING_KIND);
+    }
+
     #[test]
     fn test_trace_iterator() {
         test_helpers::test_trace_iterator(TRACING_KIND);
@@ -7,21 +7,28 @@ use crate::{errors::InvalidTraceError, sir::SIR};
 use std::{
     collections::{HashMap, HashSet},
     convert::TryFrom,
-    fmt::{self, Display}
+    fmt::{self, Display},
+    io
 };
+use ykpack::SerU128;
 pub use ykpack::{
-    BinOp, CallOperand, Constant, ConstantInt, Local, LocalDecl, LocalIndex, Operand, Place,
-    PlaceBase, Projection, Rvalue, SignedInt, Statement, Terminator, UnsignedInt
+    AbiClass, BasicBlockIndex, BinOp, CallAbi, CallOperand, Constant, ConstantInt, Local,
+    LocalDecl, LocalIndex, Operand, Place, PlaceBase, Projection, Rvalue, SignedInt, Statement,
+    Terminator, UnsignedInt
 };
 
 /// A TIR trace is conceptually a straight-line path through the SIR with guarded speculation.
 #[derive(Debug)]
 pub struct TirTrace {
     ops: Vec<TirOp>,
+    /// The index that each op had when the trace was built, before any optimisation passes.
+    orig_idxs: Vec<usize>,
     trace_inputs_local: Option<Local>,
     /// Maps each local variable to its declaration, including type.
     pub local_decls: HashMap<Local, LocalDecl>,
-    pub addr_map: HashMap<String, u64>
+    pub addr_map: HashMap<String, u64>,
+    /// Maps each symbol appearing in the trace to the number of ops it contributed.
+    inline_breakdown: HashMap<String, usize>
 }
 
 impl TirTrace {
@@ -29,20 +36,29 @@ impl TirTrace {
     /// tracer. Returns a TIR trace and the bounds the SIR trace was trimmed to, or Err if a symbol
     /// is encountered for which no SIR is available.
     pub fn new<'s>(trace: &'s dyn SirTrace) -> Result<Self, InvalidTraceError> {
+        // An empty trace has no tracer start/stop markers to trim.
+        if trace.is_empty() {
+            return Err(InvalidTraceError::EmptyTrace);
+        }
+
         let mut ops = Vec::new();
-        let mut itr = trace.into_iter().peekable();
+        let mut itr = trace.iter().peekable();
         let mut rnm = VarRenamer::new();
         let mut trace_inputs_local: Option<Local> = None;
         // Symbol name of the function currently being ignored during tracing.
         let mut ignore: Option<String> = None;
         // Maps symbol names to their virtual addresses.
         let mut addr_map: HashMap<String, u64> = HashMap::new();
+        // Counts how many ops each symbol contributed to the trace.
+        let mut inline_breakdown: HashMap<String, usize> = HashMap::new();
 
         // As we compile, we are going to check the define-use (DU) chain of our local
         // variables. No local should be used without first being defined. If that happens it's
         // likely that the user used a variable from outside the scope of the trace without
         // introducing it via `trace_locals()`.
         let mut defined_locals = HashSet::new();
+        // Scratch buffer for collecting locals, reused across statements to avoid allocating.
+        let mut locals_buf = Vec::new();
 
         let mut update_defined_locals = |renamer: &mut VarRenamer, op: &Statement| {
             // Locals reported by `maybe_defined_locals()` are only defined if they are not already
@@ -51,20 +67,13 @@ impl TirTrace {
             // FIXME: Note that we are unable to detect variables which are defined outside of the
             // traced code and which are not introduced as trace inputs. The user should not do
             // this, but it would be nice to detect that somehow and panic.
-            let newly_defined = op
-                .maybe_defined_locals()
-                .iter()
-                .filter_map(|l| {
-                    if !defined_locals.contains(l) {
-                        Some(*l)
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<Local>>();
-            defined_locals.extend(newly_defined);
+            locals_buf.clear();
+            op.maybe_defined_locals_into(&mut locals_buf);
+            defined_locals.extend(locals_buf.iter().copied());
 
-            for lcl in op.used_locals() {
+            locals_buf.clear();
+            op.used_locals_into(&mut locals_buf);
+            for lcl in locals_buf.iter().copied() {
                 // The trace inputs local is regarded as being live for the whole trace.
                 if let Some(til) = renamer.trace_inputs_local {
                     if lcl == til {
@@ -77,11 +86,13 @@ impl TirTrace {
             }
         };
 
+        let mut stmt_locals = Vec::new();
         while let Some(loc) = itr.next() {
-            let body = match SIR.bodies.get(&loc.symbol_name) {
+            let ops_before = ops.len();
+            let body = match SIR.bodies.for_loc(loc) {
                 Some(b) => b,
                 None => {
-                    return Err(InvalidTraceError::no_sir(&loc.symbol_name));
+                    return Err(InvalidTraceError::no_sir(loc.symbol_name()));
                 }
             };
 
@@ -94,20 +105,28 @@ impl TirTrace {
 
             // When adding statements to the trace, we clone them (rather than referencing the
             // statements in the SIR) so that we have the freedom to mutate them later.
-            let user_bb_idx_usize = usize::try_from(loc.bb_idx).unwrap();
+            let block = match body.block(loc.bb_idx) {
+                Some(b) => b,
+                None => {
+                    return Err(InvalidTraceError::BadBlockIndex(
+                        loc.symbol_name().to_owned(),
+                        loc.bb_idx
+                    ));
+                }
+            };
 
             // When we see the first block of a SirFunc, store its virtual address so we can turn
             // this function into a `Call` if the user decides not to trace it.
             let addr = &loc.addr;
-            if user_bb_idx_usize == 0 {
-                addr_map.insert(loc.symbol_name.to_string(), addr.unwrap());
+            if loc.bb_idx == 0 {
+                addr_map.insert(loc.symbol_name().to_string(), addr.unwrap());
             }
 
             // If a function was annotated with `do_not_trace`, skip all instructions within it as
             // well. FIXME: recursion.
             if let Some(sym) = &ignore {
-                if sym == &loc.symbol_name {
-                    match &body.blocks[user_bb_idx_usize].term {
+                if sym == loc.symbol_name() {
+                    match &block.term {
                         Terminator::Return => {
                             ignore = None;
                         }
@@ -122,10 +141,12 @@ impl TirTrace {
             // number of assigned variables in the functions outer context. For example, if a
             // function `bar` is inlined into a function `foo`, and `foo` used 5 variables, then
             // all variables in `bar` are offset by 5.
-            for stmt in body.blocks[user_bb_idx_usize].stmts.iter() {
+            for stmt in block.stmts.iter() {
                 // If the statement references a thread tracer local then discard the statement.
                 let mut skip = false;
-                for lcl in stmt.referenced_locals() {
+                stmt_locals.clear();
+                stmt.referenced_locals_into(&mut stmt_locals);
+                for lcl in stmt_locals.iter() {
                     if SIR
                         .is_thread_tracer_ty(&body.local_decls[usize::try_from(lcl.0).unwrap()].ty)
                     {
@@ -151,11 +172,16 @@ impl TirTrace {
                     Statement::Call(..) | Statement::Enter(..) | Statement::Leave => unreachable!()
                 };
 
+                // Self-assignments (`x = x`) have no effect, so there's no point keeping them.
+                if op.is_self_assign() {
+                    continue;
+                }
+
                 update_defined_locals(&mut rnm, &op);
                 ops.push(TirOp::Statement(op));
             }
 
-            let stmt = match &body.blocks[user_bb_idx_usize].term {
+            let stmt = match &block.term {
                 Terminator::Call {
                     operand: op,
                     args,
@@ -184,8 +210,19 @@ impl TirTrace {
                             // call.
                             if callbody.flags & ykpack::bodyflags::DO_NOT_TRACE != 0 {
                                 ignore = Some(callee_sym.to_string());
-                                Statement::Call(op.clone(), newargs, Some(ret_val))
+                                let abi = call_abi(&args, &dest.as_ref().unwrap().0, body, |t| {
+                                    SIR.get_ty(t)
+                                });
+                                Statement::Call(op.clone(), newargs, Some(ret_val), abi)
                             } else {
+                                check_call_args(
+                                    &args,
+                                    body,
+                                    callbody,
+                                    |t| SIR.get_ty(t),
+                                    |a, b| SIR.tys_equivalent(a, b)
+                                )?;
+
                                 // Inform VarRenamer about this function's offset, which is equal to the
                                 // number of variables assigned in the outer body.
                                 rnm.enter(callbody.local_decls.len(), ret_val.clone());
@@ -212,9 +249,25 @@ impl TirTrace {
                             // We have a symbol name but no SIR. Without SIR the callee can't
                             // appear inlined in the trace, so we should emit a native call to the
                             // symbol instead.
-                            Statement::Call(op.clone(), newargs, Some(ret_val))
+                            let abi =
+                                call_abi(&args, &dest.as_ref().unwrap().0, body, |t| SIR.get_ty(t));
+                            Statement::Call(op.clone(), newargs, Some(ret_val), abi)
                         };
                         Some(op)
+                    } else if let CallOperand::FnPtr(fn_ptr) = op {
+                        // An indirect call. We only learn which function was called at run-time,
+                        // so we always emit a native call. If the callee has SIR, its blocks
+                        // follow in the trace, and must be skipped as for `do_not_trace`.
+                        let newop = CallOperand::FnPtr(rnm.rename_place(fn_ptr, body, ops.len()));
+                        let newargs = rnm.rename_args(&args, body, ops.len());
+                        if let Some(next) = itr.peek() {
+                            if next.bb_idx == 0 && SIR.bodies.get(next.symbol_name()).is_some() {
+                                ignore = Some(next.symbol_name().to_owned());
+                            }
+                        }
+                        let abi =
+                            call_abi(&args, &dest.as_ref().unwrap().0, body, |t| SIR.get_ty(t));
+                        Some(Statement::Call(newop, newargs, Some(ret_val), abi))
                     } else {
                         todo!("Unknown callee encountered");
                     }
@@ -236,7 +289,7 @@ impl TirTrace {
             }
 
             // Convert the block terminator to a guard if necessary.
-            let guard = match body.blocks[user_bb_idx_usize].term {
+            let guard = match block.term {
                 Terminator::Goto(_)
                 | Terminator::Return
                 | Terminator::Drop { .. }
@@ -254,22 +307,14 @@ impl TirTrace {
                     // infer which value we must guard upon. We are working on the assumption that
                     // a trace can't end on a SwitchInt. i.e. that another block follows.
                     let next_blk = itr.peek().expect("no block to peek at").bb_idx;
-                    let edge_idx = target_bbs.iter().position(|e| *e == next_blk);
-                    match edge_idx {
-                        Some(idx) => Some(Guard {
-                            val: discr.clone(),
-                            kind: GuardKind::Integer(values[idx].val())
-                        }),
-                        None => {
-                            debug_assert!(next_blk == otherwise_bb);
-                            Some(Guard {
-                                val: discr.clone(),
-                                kind: GuardKind::OtherInteger(
-                                    values.iter().map(|v| v.val()).collect()
-                                )
-                            })
-                        }
-                    }
+                    switch_guard(
+                        discr,
+                        values,
+                        target_bbs,
+                        otherwise_bb,
+                        next_blk,
+                        (loc.symbol_name().to_owned(), loc.bb_idx)
+                    )
                 }
                 Terminator::Assert {
                     ref cond,
@@ -277,13 +322,18 @@ impl TirTrace {
                     ..
                 } => Some(Guard {
                     val: cond.clone(),
-                    kind: GuardKind::Boolean(*expected)
+                    kind: GuardKind::Boolean(*expected),
+                    deopt_target: (loc.symbol_name().to_owned(), loc.bb_idx)
                 })
             };
 
             if guard.is_some() {
                 ops.push(TirOp::Guard(guard.unwrap()));
             }
+
+            *inline_breakdown
+                .entry(loc.symbol_name().to_owned())
+                .or_insert(0) += ops.len() - ops_before;
         }
 
         let (local_decls, last_use_sites) = rnm.done();
@@ -303,11 +353,31 @@ impl TirTrace {
             }
         }
 
+        // Guarded values are compared at the width of their type, so only integer-like types
+        // (i.e. the types of switch discriminants) have a usable size here.
+        let discr_size = |p: &Place| {
+            let tyid = p
+                .resolved_ty(|l| local_decls.get(l), |t| SIR.get_ty(t))
+                .ok()?;
+            match SIR.get_ty(&tyid)? {
+                ty @ ykpack::Ty::SignedInt(_)
+                | ty @ ykpack::Ty::UnsignedInt(_)
+                | ty @ ykpack::Ty::Bool
+                | ty @ ykpack::Ty::Char => Some(ty.size()),
+                _ => None
+            }
+        };
+        let mut orig_idxs = (0..ops.len()).collect();
+        remove_redundant_guards(&mut ops, &mut orig_idxs, discr_size);
+        hoist_guards(&mut ops, &mut orig_idxs);
+
         Ok(Self {
             ops,
+            orig_idxs,
             trace_inputs_local,
             local_decls,
-            addr_map
+            addr_map,
+            inline_breakdown
         })
     }
 
@@ -318,6 +388,31 @@ impl TirTrace {
         unsafe { &self.ops.get_unchecked(idx) }
     }
 
+    /// Returns the index that the op at index `idx` had when the trace was built, before
+    /// optimisation passes moved or removed ops. This relates an op back to the place in the trace
+    /// it came from. The index must not be out of bounds.
+    pub fn orig_idx(&self, idx: usize) -> usize {
+        self.orig_idxs[idx]
+    }
+
+    /// Returns the statements which the guard at index `idx` was hoisted above, in trace order.
+    /// These come before the guard in the traced program, but haven't run when the guard fails, so
+    /// they must be run before execution resumes from the guard's `deopt_target`. There are none
+    /// if the guard wasn't moved. The op at `idx` must be a guard.
+    pub fn deopt_stmts(&self, idx: usize) -> impl Iterator<Item = &Statement> {
+        debug_assert!(matches!(self.ops[idx], TirOp::Guard(_)));
+        let guard_idx = self.orig_idxs[idx];
+        // Guards never move past each other, so the ops which came before the guard, but now
+        // follow it, are all statements.
+        self.ops[idx + 1..]
+            .iter()
+            .zip(self.orig_idxs[idx + 1..].iter())
+            .filter_map(move |(op, orig_idx)| match op {
+                TirOp::Statement(stmt) if *orig_idx < guard_idx => Some(stmt),
+                _ => None
+            })
+    }
+
     pub fn inputs(&self) -> &Option<Local> {
         &self.trace_inputs_local
     }
@@ -326,6 +421,332 @@ impl TirTrace {
     pub fn len(&self) -> usize {
         self.ops.len()
     }
+
+    /// Returns a map from each symbol in the trace to the number of ops that its (possibly
+    /// inlined) blocks contributed. Useful for finding out which functions dominate the trace
+    /// size. `StorageDead` statements, which are inserted after the trace is built, are not
+    /// attributed to any symbol.
+    pub fn inline_breakdown(&self) -> &HashMap<String, usize> {
+        &self.inline_breakdown
+    }
+
+    /// Renumbers the locals of the trace so that they form the dense range `$1..$k`. Inlining
+    /// offsets each callee's locals past those of its caller, which leaves the locals of a trace
+    /// sparse. `$0` is kept for the trace inputs local (if any), and the other locals are numbered
+    /// in order of first appearance. The arguments of an inlined callee stay consecutive, as
+    /// `Enter` refers to them by offset.
+    pub fn renumber_locals(&mut self) {
+        let mut map: HashMap<Local, Local> = HashMap::new();
+        if let Some(til) = self.trace_inputs_local.as_mut() {
+            map.insert(*til, Local(0));
+            *til = Local(0);
+        }
+        let mut next = 1;
+        let mut renumber = |l: Local| {
+            *map.entry(l).or_insert_with(|| {
+                next += 1;
+                Local(next - 1)
+            })
+        };
+        for op in &mut self.ops {
+            match op {
+                TirOp::Statement(stmt) => {
+                    stmt.map_locals(&mut renumber);
+                    if let Statement::Enter(_, args, _, start_idx) = stmt {
+                        // The callee's arguments are `$start_idx + 1..`, and are fresh locals, so
+                        // they can be numbered consecutively here.
+                        let nargs = u32::try_from(args.len()).unwrap();
+                        let mut first: Option<Local> = None;
+                        for i in 1..=nargs {
+                            let new = renumber(Local(*start_idx + i));
+                            let first = *first.get_or_insert(new);
+                            debug_assert_eq!(new.0, first.0 + i - 1);
+                        }
+                        if let Some(first) = first {
+                            *start_idx = first.0 - 1;
+                        }
+                    }
+                }
+                TirOp::Guard(g) => g.val.map_locals(&mut renumber)
+            }
+        }
+
+        // Any declared locals which the ops don't mention go last, in their original order.
+        let mut unmentioned = self.local_decls.keys().copied().collect::<Vec<_>>();
+        unmentioned.sort();
+        for l in unmentioned {
+            renumber(l);
+        }
+        self.local_decls = self
+            .local_decls
+            .drain()
+            .map(|(l, decl)| (renumber(l), decl))
+            .collect();
+    }
+
+    /// Returns the distinct symbols which the trace calls natively (i.e. without inlining), in
+    /// order of their first call. Their addresses must be resolved before the trace can run.
+    pub fn native_calls(&self) -> Vec<&str> {
+        let mut syms = Vec::new();
+        for op in &self.ops {
+            if let TirOp::Statement(Statement::Call(CallOperand::Fn(sym), ..)) = op {
+                if !syms.contains(&sym.as_str()) {
+                    syms.push(sym.as_str());
+                }
+            }
+        }
+        syms
+    }
+}
+
+/// Returns the guard for a `SwitchInt` terminator on `discr`, given that the trace continued to
+/// the block `next_blk`. A switch with no values always takes `otherwise_bb`, so no guard is
+/// needed.
+fn switch_guard(
+    discr: &Place,
+    values: &[SerU128],
+    target_bbs: &[BasicBlockIndex],
+    otherwise_bb: BasicBlockIndex,
+    next_blk: BasicBlockIndex,
+    deopt_target: (String, BasicBlockIndex)
+) -> Option<Guard> {
+    if values.is_empty() {
+        debug_assert!(next_blk == otherwise_bb);
+        return None;
+    }
+
+    let kind = match target_bbs.iter().position(|e| *e == next_blk) {
+        Some(idx) => GuardKind::Integer(values[idx].val()),
+        None => {
+            debug_assert!(next_blk == otherwise_bb);
+            GuardKind::OtherInteger(values.iter().map(|v| v.val()).collect())
+        }
+    };
+    Some(Guard {
+        val: discr.clone(),
+        kind,
+        deopt_target
+    })
+}
+
+/// Moves each guard up the trace to just after the last statement which may affect its value, so
+/// that fewer locals are live at the guard. A guard never moves past another guard, a call, or an
+/// inlining boundary (`Enter`/`Leave`). If the guarded place is reached through a pointer, or its
+/// local is borrowed, it also never moves past a store through a pointer, as the two may alias.
+/// `orig_idxs` holds the original
+/// index of each op, and is reordered along with `ops`, so that the statements a guard was moved
+/// above can be found if it fails (see `TirTrace::deopt_stmts()`).
+fn hoist_guards(ops: &mut Vec<TirOp>, orig_idxs: &mut Vec<usize>) {
+    let borrowed = borrowed_locals(ops);
+    let mut defined = Vec::new();
+    for idx in 0..ops.len() {
+        let val = match &ops[idx] {
+            TirOp::Guard(g) => &g.val,
+            TirOp::Statement(_) => continue
+        };
+
+        let mut to = idx;
+        while to > 0 {
+            let stmt = match &ops[to - 1] {
+                TirOp::Statement(stmt) => stmt,
+                TirOp::Guard(_) => break
+            };
+            let affects = match stmt {
+                Statement::Enter(..) | Statement::Leave => true,
+                _ => may_change(stmt, val, borrowed.as_ref(), &mut defined)
+            };
+            if affects {
+                break;
+            }
+            to -= 1;
+        }
+
+        if to < idx {
+            let guard = ops.remove(idx);
+            ops.insert(to, guard);
+            let orig_idx = orig_idxs.remove(idx);
+            orig_idxs.insert(to, orig_idx);
+        }
+    }
+}
+
+/// Removes each guard which is identical to an earlier guard, if nothing in between may have
+/// changed the guarded value. Such guards can't fail, as the earlier guard would have failed first.
+/// This typically happens when a check in a loop body is traced once per iteration. A guard is
+/// also redundant if an earlier guard requires the value to be an integer for which it passes.
+/// Such values are compared as bit patterns `val_size(place)` bytes wide, as for
+/// `GuardKind::passes()`; if `val_size` returns `None`, only identical guards are removed.
+/// `orig_idxs` holds the original index of each op, and is updated along with `ops`.
+fn remove_redundant_guards<S>(ops: &mut Vec<TirOp>, orig_idxs: &mut Vec<usize>, val_size: S)
+where
+    S: Fn(&Place) -> Option<u64>
+{
+    let borrowed = borrowed_locals(ops);
+    let mut defined = Vec::new();
+    let mut idx = 0;
+    while idx < ops.len() {
+        let guard = match &ops[idx] {
+            TirOp::Guard(g) => g,
+            TirOp::Statement(_) => {
+                idx += 1;
+                continue;
+            }
+        };
+
+        let mut redundant = false;
+        for op in ops[..idx].iter().rev() {
+            match op {
+                TirOp::Guard(prev) => {
+                    if prev.val != guard.val {
+                        continue;
+                    }
+                    redundant = prev.kind == guard.kind
+                        || match prev.kind {
+                            GuardKind::Integer(v) => val_size(&guard.val)
+                                .map_or(false, |size| guard.kind.passes(v, size)),
+                            _ => false
+                        };
+                    if redundant {
+                        break;
+                    }
+                }
+                TirOp::Statement(stmt) => {
+                    if may_change(stmt, &guard.val, borrowed.as_ref(), &mut defined) {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if redundant {
+            ops.remove(idx);
+            orig_idxs.remove(idx);
+        } else {
+            idx += 1;
+        }
+    }
+}
+
+/// Decides if `stmt` may change the value of the place `val`, either by defining its local or a
+/// local it's indexed by. A store through a pointer may change `val` if `val` is itself reached
+/// through a pointer, or if one of its locals is in `borrowed` (see `borrowed_locals()`), as the
+/// two may alias. Calls and unimplemented statements are assumed to change everything. `defined`
+/// is scratch space.
+fn may_change(
+    stmt: &Statement,
+    val: &Place,
+    borrowed: Option<&HashSet<Local>>,
+    defined: &mut Vec<Local>
+) -> bool {
+    let uses_local = |l: &Local| *l == val.local || val.projection.contains(&Projection::Index(*l));
+    let aliasable = val.projection.iter().any(|p| *p == Projection::Deref)
+        || match borrowed {
+            Some(borrowed) => borrowed.iter().any(uses_local),
+            None => true
+        };
+    let mut defines_val = |stmt: &Statement| {
+        defined.clear();
+        stmt.maybe_defined_locals_into(defined);
+        defined.iter().any(uses_local)
+    };
+    match stmt {
+        Statement::Nop | Statement::StorageDead(_) | Statement::Leave => false,
+        Statement::Assign(dest, _) => {
+            defines_val(stmt)
+                || (aliasable && dest.projection.iter().any(|p| *p == Projection::Deref))
+        }
+        Statement::Enter(..) => defines_val(stmt),
+        Statement::Call(..) | Statement::Unimplemented(_) => true
+    }
+}
+
+/// Returns the locals whose address is taken (by `Rvalue::Ref`) somewhere in `ops`, and which may
+/// therefore be changed by a store through a pointer. Returns `None` if the trace contains an
+/// unimplemented rvalue, as it may take the address of any local.
+fn borrowed_locals(ops: &[TirOp]) -> Option<HashSet<Local>> {
+    let mut borrowed = HashSet::new();
+    for op in ops {
+        if let TirOp::Statement(Statement::Assign(_, rv)) = op {
+            match rv {
+                Rvalue::Ref(place) => {
+                    borrowed.insert(place.local);
+                }
+                Rvalue::Unimplemented(_) => return None,
+                _ => ()
+            }
+        }
+    }
+    Some(borrowed)
+}
+
+/// Classifies how the arguments `args` and the destination `dest` of a native call in `body` are
+/// passed under the system ABI. This uses the operands from the SIR (i.e. before renaming), so that
+/// their types can be found in `body`. `ty` looks up a type by its ID.
+///
+/// Operands whose type can't be resolved (e.g. a dereferenced `Box`, whose type is unimplemented)
+/// are assumed to be passed in memory. A zero-sized destination (e.g. `()`) has no return value.
+fn call_abi<'t, T>(args: &[Operand], dest: &Place, body: &ykpack::Body, ty: T) -> CallAbi
+where
+    T: Fn(&ykpack::TypeId) -> Option<&'t ykpack::Ty>
+{
+    let local_decl = |l: &Local| body.local_decls.get(usize::try_from(l.0).unwrap());
+    let classify = |op: &Operand| op.abi_class(&local_decl, &ty).unwrap_or(AbiClass::Memory);
+    let ret_size = dest
+        .resolved_ty(&local_decl, &ty)
+        .ok()
+        .and_then(|t| ty(&t))
+        .and_then(|t| match t {
+            ykpack::Ty::Unimplemented(_) => None,
+            _ => Some(t.size())
+        });
+    CallAbi {
+        args: args.iter().map(classify).collect(),
+        ret: match ret_size {
+            Some(0) => None,
+            _ => Some(classify(&Operand::Place(dest.clone())))
+        }
+    }
+}
+
+/// Checks that the arguments `args` of a call in `caller` are compatible with the parameters of
+/// `callee`, which is about to be inlined. The arguments are moved into the callee's locals
+/// `$1..=$n`, so these must exist and have equivalent types. `ty` looks up a type by its ID, and
+/// `tys_eq` decides if two types are equivalent.
+///
+/// FIXME: SIR doesn't record how many parameters a function has, so we can only check that
+/// there's a local for each argument. Constant arguments, and arguments whose type can't be
+/// resolved (e.g. a dereferenced `Box`, whose type is unimplemented), aren't type checked.
+fn check_call_args<'t, T, E>(
+    args: &[Operand],
+    caller: &ykpack::Body,
+    callee: &ykpack::Body,
+    ty: T,
+    tys_eq: E
+) -> Result<(), InvalidTraceError>
+where
+    T: Fn(&ykpack::TypeId) -> Option<&'t ykpack::Ty>,
+    E: Fn(&ykpack::TypeId, &ykpack::TypeId) -> bool
+{
+    let mismatch = || InvalidTraceError::CallSignatureMismatch(callee.symbol_name.clone());
+    if args.len() >= callee.local_decls.len() {
+        return Err(mismatch());
+    }
+    for (idx, arg) in args.iter().enumerate() {
+        if let Operand::Place(p) = arg {
+            let arg_ty = match p.resolved_ty(
+                |l| caller.local_decls.get(usize::try_from(l.0).unwrap()),
+                &ty
+            ) {
+                Ok(t) => t,
+                Err(_) => continue
+            };
+            // + 1 to skip the return value.
+            if !tys_eq(&arg_ty, &callee.local_decls[idx + 1].ty) {
+                return Err(mismatch());
+            }
+        }
+    }
+    Ok(())
 }
 
 struct VarRenamer {
@@ -339,6 +760,12 @@ struct VarRenamer {
     acc: Option<u32>,
     /// Stores the return variables of inlined function calls. Used to replace `$0` during
     /// renaming.
+    ///
+    /// Invariant: `returns` always has exactly one entry fewer than `stack`, and the last entry is
+    /// the (already renamed) destination in the caller of the function currently being inlined.
+    /// Since destinations are renamed in the caller's context before `enter` pushes them, a call
+    /// whose destination is the caller's own `$0` forwards the callee's return value straight to
+    /// the caller's destination.
     returns: Vec<Place>,
     /// Used local declarations.
     /// Used to keep track of only the local declarations that are actually used in the trace.
@@ -460,22 +887,51 @@ impl VarRenamer {
             // Replace the default return variable $0 with the variable in the outer context where
             // the return value will end up after leaving the function. This saves us an
             // instruction when we compile the trace.
-            let ret = if let Some(v) = self.returns.last() {
+            let mut ret = if let Some(v) = self.returns.last() {
                 v.clone()
             } else {
                 panic!("Expected return value!")
             };
 
-            self.used_decl(
-                ret.local,
-                body.local_decls[usize::try_from(place.local.0).unwrap()].clone(),
-                op_num
-            );
+            if ret.projection.is_empty() {
+                self.used_decl(
+                    ret.local,
+                    body.local_decls[usize::try_from(place.local.0).unwrap()].clone(),
+                    op_num
+                );
+            } else {
+                // The destination is part of an aggregate (e.g. `x.0 = f()`), so the type of the
+                // destination's local isn't that of `$0`. Its declaration was already recorded
+                // when the destination was renamed in the caller.
+                self.last_local_uses.insert(ret.local, op_num);
+            }
+            // Projections on `$0` (e.g. `$0.1 = ...`) apply to the destination.
+            for proj in &place.projection {
+                let proj = self.rename_projection(proj, body, op_num);
+                ret.projection.push(proj);
+            }
             ret
         } else {
-            let mut p = place.clone();
-            p.local = self.rename_local(&p.local, body, op_num);
-            p
+            let local = self.rename_local(&place.local, body, op_num);
+            let projection = place
+                .projection
+                .iter()
+                .map(|proj| self.rename_projection(proj, body, op_num))
+                .collect();
+            Place { local, projection }
+        }
+    }
+
+    /// Renames the index local of an `Index` projection. Other projections don't contain locals.
+    fn rename_projection(
+        &mut self,
+        proj: &Projection,
+        body: &ykpack::Body,
+        op_num: usize
+    ) -> Projection {
+        match proj {
+            Projection::Index(l) => Projection::Index(self.rename_local(l, body, op_num)),
+            _ => proj.clone()
         }
     }
 
@@ -497,8 +953,21 @@ impl VarRenamer {
     }
 }
 
-impl Display for TirTrace {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl TirTrace {
+    /// Writes the textual representation of the trace to `w`, one op at a time, so that large
+    /// traces don't have to be formatted into a single string first. If `max_ops` is `Some(n)`,
+    /// only the first `n` ops are written, followed by a note saying how many were left out.
+    pub fn write_to(&self, w: &mut dyn io::Write, max_ops: Option<usize>) -> io::Result<()> {
+        let mut adapter = IoFmtAdapter { w, err: None };
+        match self.write_fmt_to(&mut adapter, max_ops) {
+            Ok(()) => Ok(()),
+            Err(fmt::Error) => Err(adapter
+                .err
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "formatting error")))
+        }
+    }
+
+    fn write_fmt_to(&self, f: &mut dyn fmt::Write, max_ops: Option<usize>) -> fmt::Result {
         writeln!(f, "local_decls:")?;
         let mut sort_decls = self
             .local_decls
@@ -524,24 +993,54 @@ impl Display for TirTrace {
         }
 
         writeln!(f, "ops:")?;
-        for op in &self.ops {
+        let max_ops = max_ops.unwrap_or(self.ops.len());
+        for op in self.ops.iter().take(max_ops) {
             writeln!(f, "  {}", op)?;
         }
+        if self.ops.len() > max_ops {
+            writeln!(f, "  ... {} more ops", self.ops.len() - max_ops)?;
+        }
         Ok(())
     }
 }
 
+/// Forwards `fmt::Write` calls to an `io::Write`, keeping hold of the first I/O error, which
+/// `fmt::Error` can't carry.
+struct IoFmtAdapter<'a> {
+    w: &'a mut dyn io::Write,
+    err: Option<io::Error>
+}
+
+impl fmt::Write for IoFmtAdapter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.w.write_all(s.as_bytes()).map_err(|e| {
+            self.err = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+impl Display for TirTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_fmt_to(f, None)
+    }
+}
+
 /// A guard states the assumptions from its position in a trace onward.
 #[derive(Debug)]
 pub struct Guard {
     /// The value to be checked if the guard is to pass.
     pub val: Place,
     /// The requirement upon `val` for the guard to pass.
-    pub kind: GuardKind
+    pub kind: GuardKind,
+    /// The symbol name and block index of the SIR block whose terminator the guard was derived
+    /// from. If the guard fails, execution must resume from that terminator, after running any
+    /// statements that the guard was hoisted above (see `TirTrace::deopt_stmts()`).
+    pub deopt_target: (String, BasicBlockIndex)
 }
 
 /// A guard states the assumptions from its position in a trace onward.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum GuardKind {
     /// The value must be equal to an integer constant.
     Integer(u128),
@@ -552,6 +1051,33 @@ pub enum GuardKind {
     Boolean(bool)
 }
 
+impl GuardKind {
+    /// Decide if the guard passes for the discriminant `discr`, which is `size` bytes wide.
+    ///
+    /// SIR stores `SwitchInt` values as `u128` bit patterns truncated to the width of the
+    /// discriminant (e.g. `-1i8` is stored as `0xff`), so both sides are truncated to `size` bytes
+    /// before comparing. This means a discriminant read with or without sign extension compares
+    /// the same.
+    pub fn passes(&self, discr: u128, size: u64) -> bool {
+        let discr = Self::truncate(discr, size);
+        match self {
+            Self::Integer(v) => Self::truncate(*v, size) == discr,
+            Self::OtherInteger(vs) => vs.iter().all(|v| Self::truncate(*v, size) != discr),
+            Self::Boolean(expect) => (discr != 0) == *expect
+        }
+    }
+
+    /// Keep only the low `size` bytes of `bits`.
+    fn truncate(bits: u128, size: u64) -> u128 {
+        debug_assert!(size <= 16);
+        if size >= 16 {
+            bits
+        } else {
+            bits & ((1u128 << (size * 8)) - 1)
+        }
+    }
+}
+
 impl fmt::Display for Guard {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "guard({}, {})", self.val, self.kind)
@@ -586,9 +1112,20 @@ impl fmt::Display for TirOp {
 
 #[cfg(test)]
 mod tests {
-    use super::TirTrace;
-    use crate::{start_tracing, TracingKind};
-    use test::black_box;
+    use super::{
+        call_abi, check_call_args, hoist_guards, remove_redundant_guards, switch_guard, Guard,
+        GuardKind, TirOp, TirTrace, VarRenamer
+    };
+    use crate::{
+        errors::InvalidTraceError, sir::SIR, start_tracing, trace, SirLoc, SirTrace, TracingKind
+    };
+    use std::{collections::HashMap, convert::TryFrom};
+    use test::{black_box, Bencher};
+    use ykpack::{
+        test_utils::TypesBuilder, AbiClass, BinOp, Body, CallAbi, CallOperand, Local, LocalDecl,
+        Operand, Place, Projection, Rvalue, SerU128, Statement, Terminator, Ty, TypeId,
+        UnsignedIntTy
+    };
 
     // Some work to trace.
     #[inline(never)]
@@ -614,6 +1151,407 @@ mod tests {
         assert!(tir_trace.len() > 0);
     }
 
+    #[test]
+    fn stop_and_lower() {
+        let trace = |fused: bool| {
+            #[cfg(tracermode = "sw")]
+            let tracer = start_tracing(Some(TracingKind::SoftwareTracing));
+            #[cfg(tracermode = "hw")]
+            let tracer = start_tracing(Some(TracingKind::HardwareTracing));
+
+            black_box(work(black_box(3), black_box(13)));
+            if fused {
+                tracer.stop_and_lower().unwrap()
+            } else {
+                let sir_trace = tracer.stop_tracing().unwrap();
+                TirTrace::new(&*sir_trace).unwrap()
+            }
+        };
+        assert_eq!(trace(true).to_string(), trace(false).to_string());
+    }
+
+    #[test]
+    fn trace_macro() {
+        let tir_trace = trace!(work(black_box(3), black_box(13))).unwrap();
+        assert!(tir_trace.len() > 0);
+        assert!(tir_trace
+            .inline_breakdown()
+            .keys()
+            .any(|sym| sym.contains("work")));
+    }
+
+    #[test]
+    fn renumber_locals() {
+        let assign = |dest, src| {
+            TirOp::Statement(Statement::Assign(
+                place(dest),
+                Rvalue::Use(Operand::from(Local(src)))
+            ))
+        };
+        let ops = vec![
+            assign(5, 1),
+            TirOp::Statement(Statement::Enter(
+                CallOperand::Fn(String::from("f")),
+                vec![Operand::from(Local(5)), Operand::from(Local(5))],
+                Some(place(9)),
+                40
+            )),
+            assign(9, 42),
+            assign(9, 41),
+            TirOp::Statement(Statement::Leave),
+            TirOp::Guard(Guard {
+                val: place(9),
+                kind: GuardKind::Integer(1),
+                deopt_target: (String::from("g"), 0)
+            }),
+            TirOp::Statement(Statement::StorageDead(Local(5))),
+        ];
+        let local_decls = [1, 5, 9, 41, 42, 50]
+            .iter()
+            .map(|l| (Local(*l), LocalDecl { ty: (0, *l) }))
+            .collect();
+        let orig_idxs = (0..ops.len()).collect();
+        let mut tt = TirTrace {
+            ops,
+            orig_idxs,
+            trace_inputs_local: Some(Local(1)),
+            local_decls,
+            addr_map: HashMap::new(),
+            inline_breakdown: HashMap::new()
+        };
+        tt.renumber_locals();
+
+        let strs = (0..tt.len())
+            .map(|i| tt.op(i).to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            strs,
+            vec![
+                "$1 = $0",
+                "enter(f, [$1, $1], $2, 2)",
+                "$2 = $4",
+                "$2 = $3",
+                "leave",
+                "guard($2, integer(1))",
+                "dead($1)",
+            ]
+        );
+        // The callee's arguments are still found by offset.
+        match tt.op(1) {
+            TirOp::Statement(stmt) => {
+                assert_eq!(stmt.maybe_defined_locals(), vec![Local(3), Local(4)])
+            }
+            _ => unreachable!()
+        }
+        assert_eq!(tt.inputs(), &Some(Local(0)));
+        // The locals are dense, and keep their declarations. `$50` was never mentioned.
+        for (new, old) in [1, 5, 9, 41, 42, 50].iter().enumerate() {
+            let new = Local(u32::try_from(new).unwrap());
+            assert_eq!(tt.local_decls[&new], LocalDecl { ty: (0, *old) });
+        }
+        assert_eq!(tt.local_decls.len(), 6);
+
+        // Without a trace inputs local, `$0` is left unused.
+        let ops = vec![assign(7, 3)];
+        let local_decls = [3, 7]
+            .iter()
+            .map(|l| (Local(*l), LocalDecl { ty: (0, *l) }))
+            .collect();
+        let mut tt = TirTrace {
+            ops,
+            orig_idxs: vec![0],
+            trace_inputs_local: None,
+            local_decls,
+            addr_map: HashMap::new(),
+            inline_breakdown: HashMap::new()
+        };
+        tt.renumber_locals();
+        assert_eq!(tt.op(0).to_string(), "$1 = $2");
+        assert_eq!(tt.local_decls[&Local(1)], LocalDecl { ty: (0, 7) });
+        assert_eq!(tt.local_decls[&Local(2)], LocalDecl { ty: (0, 3) });
+        assert_eq!(tt.local_decls.len(), 2);
+    }
+
+    #[test]
+    fn native_calls() {
+        let call = |sym: &str| {
+            TirOp::Statement(Statement::Call(
+                CallOperand::Fn(String::from(sym)),
+                Vec::new(),
+                Some(place(1)),
+                CallAbi {
+                    args: Vec::new(),
+                    ret: None
+                }
+            ))
+        };
+        let tt = TirTrace {
+            ops: vec![
+                call("getpid"),
+                TirOp::Statement(Statement::Nop),
+                call("abs"),
+                call("getpid"),
+            ],
+            orig_idxs: vec![0, 1, 2, 3],
+            trace_inputs_local: None,
+            local_decls: HashMap::new(),
+            addr_map: HashMap::new(),
+            inline_breakdown: HashMap::new()
+        };
+        assert_eq!(tt.native_calls(), vec!["getpid", "abs"]);
+    }
+
+    #[inline(never)]
+    fn add_one(x: u8) -> u8 {
+        x + 1
+    }
+
+    #[ignore] // FIXME: ykrustc doesn't emit `CallOperand::FnPtr` yet.
+    #[test]
+    fn indirect_call() {
+        #[cfg(tracermode = "sw")]
+        let tracer = start_tracing(Some(TracingKind::SoftwareTracing));
+        #[cfg(tracermode = "hw")]
+        let tracer = start_tracing(Some(TracingKind::HardwareTracing));
+
+        let f: fn(u8) -> u8 = black_box(add_one);
+        black_box(f(black_box(1)));
+        let sir_trace = tracer.stop_tracing().unwrap();
+        let tir_trace = TirTrace::new(&*sir_trace).unwrap();
+
+        let mut num_calls = 0;
+        for idx in 0..tir_trace.len() {
+            match tir_trace.op(idx) {
+                TirOp::Statement(Statement::Call(CallOperand::FnPtr(_), ..)) => num_calls += 1,
+                // `add_one` must not also appear inlined.
+                TirOp::Statement(Statement::Enter(op, ..)) => {
+                    assert!(!op.symbol().unwrap().contains("add_one"))
+                }
+                _ => ()
+            }
+        }
+        assert_eq!(num_calls, 1);
+    }
+
+    // Checks that each guard's deopt target is a block ending in a terminator that guards are
+    // derived from.
+    #[test]
+    fn guard_deopt_target() {
+        #[cfg(tracermode = "sw")]
+        let tracer = start_tracing(Some(TracingKind::SoftwareTracing));
+        #[cfg(tracermode = "hw")]
+        let tracer = start_tracing(Some(TracingKind::HardwareTracing));
+
+        black_box(work(black_box(3), black_box(13)));
+        let sir_trace = tracer.stop_tracing().unwrap();
+        let tir_trace = TirTrace::new(&*sir_trace).unwrap();
+
+        let mut num_guards = 0;
+        for idx in 0..tir_trace.len() {
+            if let TirOp::Guard(g) = tir_trace.op(idx) {
+                num_guards += 1;
+                let (sym, bb_idx) = &g.deopt_target;
+                let body = SIR.bodies.get(sym).unwrap();
+                match (&g.kind, &body.block(*bb_idx).unwrap().term) {
+                    (GuardKind::Boolean(_), Terminator::Assert { .. })
+                    | (GuardKind::Integer(_), Terminator::SwitchInt { .. })
+                    | (GuardKind::OtherInteger(_), Terminator::SwitchInt { .. }) => (),
+                    (kind, term) => panic!("{} derived from {}", kind, term)
+                }
+            }
+        }
+        // `work` loops, so there must be at least one guard.
+        assert!(num_guards > 0);
+    }
+
+    #[test]
+    fn write_to() {
+        #[cfg(tracermode = "sw")]
+        let tracer = start_tracing(Some(TracingKind::SoftwareTracing));
+        #[cfg(tracermode = "hw")]
+        let tracer = start_tracing(Some(TracingKind::HardwareTracing));
+
+        black_box(work(black_box(3), black_box(13)));
+        let sir_trace = tracer.stop_tracing().unwrap();
+        let tir_trace = TirTrace::new(&*sir_trace).unwrap();
+
+        let mut buf = Vec::new();
+        tir_trace.write_to(&mut buf, None).unwrap();
+        let full = String::from_utf8(buf).unwrap();
+        assert_eq!(full, format!("{}", tir_trace));
+
+        let mut buf = Vec::new();
+        tir_trace.write_to(&mut buf, Some(1)).unwrap();
+        let truncated = String::from_utf8(buf).unwrap();
+        let first_op = format!("ops:\n  {}\n", tir_trace.op(0));
+        assert!(truncated.contains(&first_op));
+        assert!(truncated.ends_with(&format!("  ... {} more ops\n", tir_trace.len() - 1)));
+    }
+
+    // A body whose local `$i` has type `(0, tys[i])`.
+    fn body_with_tys(sym: &str, tys: &[u32]) -> Body {
+        let mut body = dummy_body(0);
+        body.symbol_name = String::from(sym);
+        body.local_decls = tys.iter().map(|t| LocalDecl { ty: (0, *t) }).collect();
+        body
+    }
+
+    #[test]
+    fn call_signature() {
+        let caller = body_with_tys("caller", &[0, 1, 2]);
+        let callee = body_with_tys("callee", &[0, 1, 2, 3]);
+        let check =
+            |args: &[Operand]| check_call_args(args, &caller, &callee, |_| None, |a, b| a == b);
+
+        assert!(check(&[Operand::from(Local(1)), Operand::from(Local(2))]).is_ok());
+        // Fewer arguments than locals is fine, since we don't know the parameter count.
+        assert!(check(&[Operand::from(Local(1))]).is_ok());
+        match check(&[Operand::from(Local(2)), Operand::from(Local(1))]) {
+            Err(InvalidTraceError::CallSignatureMismatch(sym)) => assert_eq!(sym, "callee"),
+            _ => panic!("expected a CallSignatureMismatch error")
+        }
+        let too_many = vec![Operand::from(Local(1)); 4];
+        match check(&too_many) {
+            Err(InvalidTraceError::CallSignatureMismatch(_)) => (),
+            _ => panic!("expected a CallSignatureMismatch error")
+        }
+
+        // The type of a dereferenced `Box` can't be resolved, so the argument isn't checked and
+        // the callee can still be inlined.
+        let boxed = Ty::Unimplemented(String::from("Box<u8>"));
+        let deref_box = Operand::Place(Place {
+            local: Local(1),
+            projection: vec![Projection::Deref]
+        });
+        assert!(check_call_args(
+            &[deref_box],
+            &caller,
+            &callee,
+            |_| Some(&boxed),
+            |a, b| a == b
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn native_call_abi() {
+        let mut tys = TypesBuilder::new(0);
+        tys.add_tuple("()", &[]);
+        tys.add("u8", Ty::UnsignedInt(UnsignedIntTy::U8));
+        tys.add("Box<u8>", Ty::Unimplemented(String::from("Box<u8>")));
+        let tys = tys.build();
+        let ty = |t: &TypeId| tys.types.get(usize::try_from(t.1).unwrap());
+        let body = body_with_tys("f", &[0, 1, 2]);
+        let deref_box = Operand::Place(Place {
+            local: Local(2),
+            projection: vec![Projection::Deref]
+        });
+
+        // The type of a dereferenced `Box` can't be resolved, so it's assumed to be in memory.
+        let abi = call_abi(&[Operand::from(Local(1)), deref_box], &place(1), &body, ty);
+        assert_eq!(abi.args, vec![AbiClass::Integer, AbiClass::Memory]);
+        assert_eq!(abi.ret, Some(AbiClass::Integer));
+        // A call returning `()` has no return value.
+        assert_eq!(call_abi(&[], &place(0), &body, ty).ret, None);
+    }
+
+    // A SIR trace backed by a vector, so that tests can make up or tamper with locations.
+    #[derive(Debug)]
+    struct VecSirTrace(Vec<SirLoc>);
+
+    impl SirTrace for VecSirTrace {
+        fn raw_len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn raw_loc(&self, idx: usize) -> &SirLoc {
+            &self.0[idx]
+        }
+
+        fn input(&self) -> Local {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn empty_trace() {
+        let trace = VecSirTrace(Vec::new());
+        assert!(trace.is_empty());
+        match TirTrace::new(&trace) {
+            Err(InvalidTraceError::EmptyTrace) => (),
+            _ => panic!("expected an EmptyTrace error")
+        }
+    }
+
+    #[test]
+    fn bad_block_index() {
+        #[cfg(tracermode = "sw")]
+        let tracer = start_tracing(Some(TracingKind::SoftwareTracing));
+        #[cfg(tracermode = "hw")]
+        let tracer = start_tracing(Some(TracingKind::HardwareTracing));
+
+        black_box(work(black_box(3), black_box(13)));
+        let sir_trace = tracer.stop_tracing().unwrap();
+
+        // Point all of the locations in `work` at a block which doesn't exist.
+        let locs = (0..sir_trace.raw_len())
+            .map(|i| {
+                let loc = sir_trace.raw_loc(i);
+                let bb_idx = if loc.symbol_name().contains("work") {
+                    u32::MAX
+                } else {
+                    loc.bb_idx
+                };
+                SirLoc::new(loc.symbol_name().to_owned(), bb_idx, loc.addr)
+            })
+            .collect();
+        match TirTrace::new(&VecSirTrace(locs)) {
+            Err(InvalidTraceError::BadBlockIndex(sym, bb_idx)) => {
+                assert!(sym.contains("work"));
+                assert_eq!(bb_idx, u32::MAX);
+            }
+            _ => panic!("expected a BadBlockIndex error")
+        }
+    }
+
+    #[inline(never)]
+    fn inline_a(x: usize) -> usize {
+        x + 1
+    }
+
+    #[inline(never)]
+    fn inline_b(x: usize) -> usize {
+        let y = x * 2;
+        y + 3
+    }
+
+    #[test]
+    fn inline_breakdown() {
+        #[cfg(tracermode = "sw")]
+        let tracer = start_tracing(Some(TracingKind::SoftwareTracing));
+        #[cfg(tracermode = "hw")]
+        let tracer = start_tracing(Some(TracingKind::HardwareTracing));
+
+        let res = black_box(inline_b(inline_a(black_box(1))));
+        let sir_trace = tracer.stop_tracing().unwrap();
+        let tir_trace = TirTrace::new(&*sir_trace).unwrap();
+        assert_eq!(res, 7);
+
+        let count = |name: &str| -> usize {
+            tir_trace
+                .inline_breakdown()
+                .iter()
+                .filter(|(sym, _)| sym.contains(name))
+                .map(|(_, n)| *n)
+                .sum()
+        };
+        assert!(count("inline_a") > 0);
+        assert!(count("inline_b") > 0);
+        // Every op built from the SIR is attributed to exactly one symbol.
+        let total: usize = tir_trace.inline_breakdown().values().sum();
+        assert!(total <= tir_trace.len());
+    }
+
     #[test]
     #[should_panic]
     fn use_undefined_var() {
@@ -627,4 +1565,450 @@ mod tests {
         let sir_trace = tracer.stop_tracing().unwrap();
         let _tir_trace = TirTrace::new(&*sir_trace).unwrap();
     }
+
+    // Checks that a guard moves up to just after the statement defining the guarded local.
+    #[test]
+    fn guard_hoisting() {
+        let assign = |dest, src| {
+            TirOp::Statement(Statement::Assign(
+                place(dest),
+                Rvalue::Use(Operand::from(Local(src)))
+            ))
+        };
+        let guard = |l| {
+            TirOp::Guard(Guard {
+                val: place(l),
+                kind: GuardKind::Integer(1),
+                deopt_target: (String::from("f"), 0)
+            })
+        };
+        let mut ops = vec![
+            assign(1, 0),
+            assign(2, 0),
+            assign(3, 2),
+            TirOp::Statement(Statement::Leave),
+            assign(4, 3),
+            assign(5, 4),
+            guard(1),
+            guard(4),
+        ];
+        let mut orig_idxs = (0..ops.len()).collect();
+        hoist_guards(&mut ops, &mut orig_idxs);
+        let strs = ops.iter().map(|op| op.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            strs,
+            vec![
+                "$1 = $0",
+                "$2 = $0",
+                "$3 = $2",
+                "leave",
+                "guard($1, integer(1))",
+                "$4 = $3",
+                "guard($4, integer(1))",
+                "$5 = $4",
+            ]
+        );
+        // The moved guards keep their original indices.
+        assert_eq!(orig_idxs, vec![0, 1, 2, 3, 6, 4, 7, 5]);
+    }
+
+    // Checks that when a hoisted guard fails, the statements it was moved above can be found, so
+    // that the state at the guard's original position can be recreated.
+    #[test]
+    fn hoisted_guard_deopt() {
+        let assign = |dest| {
+            TirOp::Statement(Statement::Assign(
+                place(dest),
+                Rvalue::Use(Operand::from(Local(0)))
+            ))
+        };
+        let guard = |l| {
+            TirOp::Guard(Guard {
+                val: place(l),
+                kind: GuardKind::Integer(1),
+                deopt_target: (String::from("f"), 0)
+            })
+        };
+        let mut ops = vec![assign(2), assign(1), assign(3), guard(1), guard(2)];
+        let mut orig_idxs = (0..ops.len()).collect();
+        hoist_guards(&mut ops, &mut orig_idxs);
+        let tt = TirTrace {
+            ops,
+            orig_idxs,
+            trace_inputs_local: None,
+            local_decls: HashMap::new(),
+            addr_map: HashMap::new(),
+            inline_breakdown: HashMap::new()
+        };
+        assert_eq!(
+            (0..tt.len())
+                .map(|i| tt.op(i).to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "$2 = $0",
+                "$1 = $0",
+                "guard($1, integer(1))",
+                "guard($2, integer(1))",
+                "$3 = $0",
+            ]
+        );
+
+        // The statements which have run when the guard at `idx` fails, followed by those which
+        // must be run before resuming.
+        let deopt_state = |idx| {
+            (0..idx)
+                .filter_map(|i| match tt.op(i) {
+                    TirOp::Statement(stmt) => Some(stmt),
+                    TirOp::Guard(_) => None
+                })
+                .chain(tt.deopt_stmts(idx))
+                .map(|stmt| stmt.to_string())
+                .collect::<Vec<_>>()
+        };
+        // Both guards come after all three assignments in the traced program.
+        for idx in 2..4 {
+            assert_eq!(deopt_state(idx), vec!["$2 = $0", "$1 = $0", "$3 = $0"]);
+        }
+    }
+
+    #[test]
+    fn redundant_guards() {
+        let assign = |dest, src| {
+            TirOp::Statement(Statement::Assign(
+                place(dest),
+                Rvalue::Use(Operand::from(Local(src)))
+            ))
+        };
+        let guard = |l, v| {
+            TirOp::Guard(Guard {
+                val: place(l),
+                kind: GuardKind::Integer(v),
+                deopt_target: (String::from("f"), 0)
+            })
+        };
+        let mut ops = vec![
+            guard(1, 1),
+            assign(2, 1),
+            TirOp::Statement(Statement::Leave),
+            guard(2, 0),
+            // $1 hasn't changed, so this can't fail.
+            guard(1, 1),
+            assign(1, 2),
+            // $1 may have changed, so this has to stay.
+            guard(1, 1),
+            guard(1, 2),
+        ];
+        let mut orig_idxs = (0..ops.len()).collect();
+        remove_redundant_guards(&mut ops, &mut orig_idxs, |_| None);
+        let strs = ops.iter().map(|op| op.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            strs,
+            vec![
+                "guard($1, integer(1))",
+                "$2 = $1",
+                "leave",
+                "guard($2, integer(0))",
+                "$1 = $2",
+                "guard($1, integer(1))",
+                "guard($1, integer(2))",
+            ]
+        );
+        // The surviving ops keep their original indices.
+        assert_eq!(orig_idxs, vec![0, 1, 2, 3, 5, 6, 7]);
+    }
+
+    // Checks that a store through a pointer is assumed to change a local whose address was taken,
+    // so that guards on that local are neither removed nor hoisted above the store.
+    #[test]
+    fn aliased_guards() {
+        let guard = |l| {
+            TirOp::Guard(Guard {
+                val: place(l),
+                kind: GuardKind::Integer(1),
+                deopt_target: (String::from("f"), 0)
+            })
+        };
+        // `$2 = &$1; (*$2) = $3` changes `$1`, but not `$4`.
+        let ops = || {
+            let deref_2 = Place {
+                local: Local(2),
+                projection: vec![Projection::Deref]
+            };
+            vec![
+                TirOp::Statement(Statement::Assign(place(1), Rvalue::from(Local(0)))),
+                TirOp::Statement(Statement::Assign(place(4), Rvalue::from(Local(0)))),
+                TirOp::Statement(Statement::Assign(place(2), Rvalue::Ref(place(1)))),
+                TirOp::Statement(Statement::Assign(deref_2, Rvalue::from(Local(3)))),
+            ]
+        };
+
+        // A guard before the store doesn't make an identical guard after it redundant.
+        let remove = |l| {
+            let mut ops = ops();
+            ops.insert(2, guard(l));
+            ops.push(guard(l));
+            let mut orig_idxs = (0..ops.len()).collect::<Vec<_>>();
+            remove_redundant_guards(&mut ops, &mut orig_idxs, |_| None);
+            orig_idxs
+        };
+        assert_eq!(remove(1), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(remove(4), vec![0, 1, 2, 3, 4]);
+
+        // A guard after the store can't be hoisted above it.
+        let hoist = |l| {
+            let mut ops = ops();
+            ops.push(guard(l));
+            let mut orig_idxs = (0..ops.len()).collect::<Vec<_>>();
+            hoist_guards(&mut ops, &mut orig_idxs);
+            orig_idxs
+        };
+        assert_eq!(hoist(1), vec![0, 1, 2, 3, 4]);
+        assert_eq!(hoist(4), vec![0, 1, 4, 2, 3]);
+    }
+
+    // Checks that a guard is removed if an earlier guard pins the value to one for which it
+    // passes, comparing at the width of the guarded value.
+    #[test]
+    fn implied_guards() {
+        let guard = |kind| {
+            TirOp::Guard(Guard {
+                val: place(1),
+                kind,
+                deopt_target: (String::from("f"), 0)
+            })
+        };
+        // `$1` is an `i8` which was switched on with `-1i8`, stored in SIR as `0xff`.
+        let mut ops = vec![
+            guard(GuardKind::Integer(0xff)),
+            guard(GuardKind::OtherInteger(vec![0, 1])),
+            // A sign-extended `-1` is the same value at the width of an `i8`.
+            guard(GuardKind::Integer(u128::max_value())),
+            guard(GuardKind::OtherInteger(vec![0xff])),
+        ];
+        let mut orig_idxs = (0..ops.len()).collect();
+        remove_redundant_guards(&mut ops, &mut orig_idxs, |_| Some(1));
+        let strs = ops.iter().map(|op| op.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            strs,
+            vec!["guard($1, integer(255))", "guard($1, other_integer([255]))"]
+        );
+        assert_eq!(orig_idxs, vec![0, 3]);
+    }
+
+    #[test]
+    fn switch_guards() {
+        let vals = vec![SerU128::new(1), SerU128::new(5)];
+        let target = || (String::from("f"), 0);
+        let guard_kind = |values: &[SerU128], targets: &[u32], next| {
+            switch_guard(&place(1), values, targets, 9, next, target()).map(|g| g.kind.to_string())
+        };
+
+        assert_eq!(
+            guard_kind(&vals, &[2, 3], 3),
+            Some(String::from("integer(5)"))
+        );
+        assert_eq!(
+            guard_kind(&vals, &[2, 3], 9),
+            Some(String::from("other_integer([1, 5])"))
+        );
+        // A switch with only an otherwise edge is unconditional.
+        assert_eq!(guard_kind(&[], &[], 9), None);
+    }
+
+    // A body with `n` locals, used to drive the renamer without any real SIR.
+    fn dummy_body(n: usize) -> Body {
+        Body {
+            symbol_name: String::from("dummy"),
+            blocks: Vec::new(),
+            flags: 0,
+            trace_inputs_local: None,
+            local_decls: vec![LocalDecl { ty: (0, 0) }; n]
+        }
+    }
+
+    fn place(l: u32) -> Place {
+        Place::from(Local(l))
+    }
+
+    #[test]
+    fn guard_signed_discr() {
+        // A switch on `-1i8` is stored in SIR as the bit pattern `0xff`.
+        let neg_one = GuardKind::Integer(0xff);
+        assert!(neg_one.passes(-1i8 as u128, 1));
+        assert!(neg_one.passes(0xff, 1));
+        assert!(!neg_one.passes(-2i8 as u128, 1));
+        assert!(!neg_one.passes(-1i16 as u128, 2));
+        assert!(GuardKind::Integer(-1i64 as u64 as u128).passes(-1i64 as u128, 8));
+
+        let other = GuardKind::OtherInteger(vec![0xff, 0]);
+        assert!(!other.passes(-1i8 as u128, 1));
+        assert!(other.passes(-2i8 as u128, 1));
+        assert!(GuardKind::Boolean(true).passes(1, 1));
+    }
+
+    // Checks that return values are routed to the right destination when `f` calls `g`, which in
+    // turn calls `h`.
+    #[test]
+    fn nested_returns() {
+        let body = dummy_body(4);
+        let mut rnm = VarRenamer::new();
+        rnm.init_acc(4);
+
+        // In `f`: `$1 = g(...)`.
+        let g_dest = rnm.rename_place(&place(1), &body, 0);
+        assert_eq!(g_dest, place(1));
+        rnm.enter(4, g_dest);
+
+        // In `g`: `$2 = h(...)`.
+        let h_dest = rnm.rename_place(&place(2), &body, 1);
+        assert_eq!(h_dest, place(6));
+        rnm.enter(4, h_dest);
+
+        // In `h`: the return value goes to `g`'s destination, other locals are offset.
+        assert_eq!(rnm.rename_place(&place(0), &body, 2), place(6));
+        assert_eq!(rnm.rename_place(&place(1), &body, 2), place(9));
+        rnm.leave();
+
+        // Back in `g`: its return value goes to `f`'s destination.
+        assert_eq!(rnm.rename_place(&place(0), &body, 3), place(1));
+        assert_eq!(rnm.rename_place(&place(1), &body, 3), place(5));
+        rnm.leave();
+
+        // Back in `f`.
+        assert_eq!(rnm.rename_place(&place(1), &body, 4), place(1));
+    }
+
+    // Checks that when `g` returns the result of `h` directly (`$0 = h(...)`), `h`'s return value
+    // is forwarded to `f`'s destination.
+    #[test]
+    fn nested_returns_forwarded() {
+        let body = dummy_body(3);
+        let mut rnm = VarRenamer::new();
+        rnm.init_acc(3);
+
+        let g_dest = rnm.rename_place(&place(2), &body, 0);
+        rnm.enter(3, g_dest);
+        let h_dest = rnm.rename_place(&place(0), &body, 1);
+        assert_eq!(h_dest, place(2));
+        rnm.enter(3, h_dest);
+        assert_eq!(rnm.rename_place(&place(0), &body, 2), place(2));
+        rnm.leave();
+        assert_eq!(rnm.rename_place(&place(0), &body, 3), place(2));
+        rnm.leave();
+    }
+
+    // Checks that a call result stored into a field (`$1.0 = g(...)`) is routed to that field.
+    #[test]
+    fn projected_call_dest() {
+        // Local types: `f`'s `$1` is a struct, `g`'s `$0` is the type of its first field.
+        let f = body_with_tys("f", &[0, 1]);
+        let g = body_with_tys("g", &[2, 3]);
+        let mut rnm = VarRenamer::new();
+        rnm.init_acc(2);
+
+        let field = |l, idxs: &[u32]| Place {
+            local: Local(l),
+            projection: idxs.iter().map(|i| Projection::Field(*i)).collect()
+        };
+        let dest = rnm.rename_place(&field(1, &[0]), &f, 0);
+        assert_eq!(dest, field(1, &[0]));
+        rnm.enter(2, dest);
+
+        assert_eq!(rnm.rename_place(&place(0), &g, 1), field(1, &[0]));
+        assert_eq!(rnm.rename_place(&field(0, &[1]), &g, 2), field(1, &[0, 1]));
+        rnm.leave();
+
+        // `$1` keeps the struct type from `f`, but is used until the assignment in `g`.
+        let (decls, last_uses) = rnm.done();
+        assert_eq!(decls[&Local(1)], LocalDecl { ty: (0, 1) });
+        assert_eq!(last_uses[&Local(1)], 2);
+    }
+
+    #[test]
+    fn index_projections() {
+        let f = body_with_tys("f", &[0, 1, 2]);
+        let g = body_with_tys("g", &[0, 1, 2]);
+        let mut rnm = VarRenamer::new();
+        rnm.init_acc(3);
+        let index = |l, i| Place {
+            local: Local(l),
+            projection: vec![Projection::Index(Local(i))]
+        };
+
+        // The index local is renamed along with the indexed local.
+        let dest = rnm.rename_place(&index(1, 2), &f, 0);
+        assert_eq!(dest, index(1, 2));
+        rnm.enter(3, place(0));
+        assert_eq!(rnm.rename_place(&index(1, 2), &g, 1), index(4, 5));
+        // An index applied to the return local is renamed in the callee's context.
+        assert_eq!(rnm.rename_place(&index(0, 2), &g, 2), index(0, 5));
+        rnm.leave();
+
+        let (decls, last_uses) = rnm.done();
+        assert_eq!(decls[&Local(5)], LocalDecl { ty: (0, 2) });
+        assert_eq!(last_uses[&Local(5)], 2);
+
+        // A guard on an indexed place can't be hoisted past a definition of the index.
+        let mut ops = vec![
+            TirOp::Statement(Statement::Assign(place(2), Rvalue::from(Local(3)))),
+            TirOp::Guard(Guard {
+                val: index(1, 2),
+                kind: GuardKind::Integer(1),
+                deopt_target: (String::from("f"), 0)
+            }),
+        ];
+        let mut orig_idxs = vec![0, 1];
+        hoist_guards(&mut ops, &mut orig_idxs);
+        assert!(matches!(ops[1], TirOp::Guard(_)));
+        assert_eq!(orig_idxs, vec![0, 1]);
+    }
+
+    // A large straight-line trace of statements for benchmarking local collection.
+    fn big_trace() -> Vec<Statement> {
+        (0..10000u32)
+            .map(|i| {
+                Statement::Assign(
+                    place(i + 2),
+                    Rvalue::BinaryOp(
+                        BinOp::Add,
+                        Operand::from(Local(i)),
+                        Operand::from(Local(i + 1))
+                    )
+                )
+            })
+            .collect()
+    }
+
+    // A simple liveness pass, finding the last use of each local.
+    #[bench]
+    fn bench_liveness_alloc(b: &mut Bencher) {
+        let stmts = big_trace();
+        b.iter(|| {
+            let mut last_uses = vec![0; stmts.len() + 2];
+            for (i, stmt) in stmts.iter().enumerate() {
+                for l in stmt.referenced_locals() {
+                    last_uses[l.0 as usize] = i;
+                }
+            }
+            black_box(last_uses)
+        });
+    }
+
+    // The same pass as above, but reusing a buffer across statements.
+    #[bench]
+    fn bench_liveness_into(b: &mut Bencher) {
+        let stmts = big_trace();
+        b.iter(|| {
+            let mut last_uses = vec![0; stmts.len() + 2];
+            let mut buf = Vec::new();
+            for (i, stmt) in stmts.iter().enumerate() {
+                buf.clear();
+                stmt.referenced_locals_into(&mut buf);
+                for l in &buf {
+                    last_uses[l.0 as usize] = i;
+                }
+            }
+            black_box(last_uses)
+        });
+    }
 }